@@ -0,0 +1,205 @@
+//! Converts ASS event text (as returned by mpv's `sub-text/ass`) into a small,
+//! safe HTML subset: `<b>`, `<i>`, `<u>`, `<s>`, `<br>` and
+//! `<span style="color:#rrggbb">`. Every other override tag is dropped.
+
+#[derive(Clone, Default, PartialEq)]
+struct Style {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strike: bool,
+    color: Option<String>,
+    drawing: bool,
+}
+
+pub fn to_html(ass: &str) -> String {
+    let mut html = String::new();
+    let mut style = Style::default();
+    let mut run = String::new();
+    let mut chars = ass.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                let mut block = String::new();
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        break;
+                    }
+                    block.push(c);
+                }
+                let mut next = style.clone();
+                apply_overrides(&block, &mut next);
+                if next != style {
+                    flush_run(&mut html, &mut run, &style);
+                    style = next;
+                }
+            }
+            '\\' if matches!(chars.peek(), Some('N' | 'n' | 'h')) => match chars.next() {
+                Some('h') => run.push('\u{a0}'),
+                _ => {
+                    flush_run(&mut html, &mut run, &style);
+                    html.push_str("<br>");
+                }
+            },
+            '\n' => {
+                flush_run(&mut html, &mut run, &style);
+                html.push_str("<br>");
+            }
+            '\r' => {}
+            _ if style.drawing => {}
+            _ => run.push(c),
+        }
+    }
+    flush_run(&mut html, &mut run, &style);
+    html
+}
+
+/// Applies the tags of one `{...}` block. Blocks without a backslash are
+/// comments.
+fn apply_overrides(block: &str, style: &mut Style) {
+    for tag in block.split('\\').skip(1) {
+        let tag = tag.trim();
+        if let Some(arg) = tag.strip_prefix('b') {
+            if let Some(on) = parse_toggle(arg) {
+                style.bold = on;
+            }
+        } else if let Some(arg) = tag.strip_prefix('i') {
+            if let Some(on) = parse_toggle(arg) {
+                style.italic = on;
+            }
+        } else if let Some(arg) = tag.strip_prefix('u') {
+            if let Some(on) = parse_toggle(arg) {
+                style.underline = on;
+            }
+        } else if let Some(arg) = tag.strip_prefix('s') {
+            if let Some(on) = parse_toggle(arg) {
+                style.strike = on;
+            }
+        } else if let Some(arg) = tag.strip_prefix("1c").or_else(|| tag.strip_prefix('c'))
+            && (arg.is_empty() || arg.starts_with('&'))
+        {
+            style.color = parse_color(arg);
+        } else if let Some(arg) = tag.strip_prefix('p') {
+            if let Ok(level) = arg.parse::<u32>() {
+                style.drawing = level > 0;
+            }
+        } else if tag.starts_with('r') {
+            *style = Style::default();
+        }
+    }
+}
+
+/// `\b1`, `\b0`, `\b700`; an empty argument resets to off. Anything else
+/// (e.g. `\blur2`, `\bord3`) is a different tag.
+fn parse_toggle(arg: &str) -> Option<bool> {
+    if arg.is_empty() {
+        return Some(false);
+    }
+    arg.parse::<u32>().ok().map(|n| n > 0)
+}
+
+/// ASS colors are `&HBBGGRR&`, optionally with a leading alpha byte. An empty
+/// argument resets to the style default.
+fn parse_color(arg: &str) -> Option<String> {
+    let hex = arg
+        .trim_start_matches('&')
+        .trim_start_matches(['H', 'h'])
+        .trim_end_matches('&');
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    let (b, g, r) = ((value >> 16) & 0xff, (value >> 8) & 0xff, value & 0xff);
+    Some(format!("#{:02x}{:02x}{:02x}", r, g, b))
+}
+
+fn flush_run(html: &mut String, run: &mut String, style: &Style) {
+    if run.is_empty() {
+        return;
+    }
+
+    let mut close = Vec::new();
+    for (on, tag) in [
+        (style.bold, "b"),
+        (style.italic, "i"),
+        (style.underline, "u"),
+        (style.strike, "s"),
+    ] {
+        if on {
+            html.push_str(&format!("<{}>", tag));
+            close.push(format!("</{}>", tag));
+        }
+    }
+    if let Some(color) = &style.color {
+        html.push_str(&format!("<span style=\"color:{}\">", color));
+        close.push("</span>".to_string());
+    }
+
+    escape_into(html, run);
+    for tag in close.iter().rev() {
+        html.push_str(tag);
+    }
+    run.clear();
+}
+
+fn escape_into(html: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            '\u{a0}' => html.push_str("&nbsp;"),
+            _ => html.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_escaped() {
+        assert_eq!(
+            to_html(r#"<b>Tom & "Jerry"</b>"#),
+            "&lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;"
+        );
+        assert_eq!(to_html(r"a\hb"), "a&nbsp;b");
+    }
+
+    #[test]
+    fn unbalanced_tags_produce_balanced_html() {
+        assert_eq!(to_html(r"{\b1}never closed"), "<b>never closed</b>");
+        assert_eq!(to_html(r"plain{\i0} still plain"), "plain still plain");
+        assert_eq!(
+            to_html(r"{\i1}a{\b1}b{\i0}c"),
+            "<i>a</i><b><i>b</i></b><b>c</b>"
+        );
+        assert_eq!(to_html(r"cut off{\b1"), "cut off");
+    }
+
+    #[test]
+    fn drawings_are_dropped() {
+        assert_eq!(to_html(r"{\p1}m 0 0 l 100 0 100 100{\p0}text"), "text");
+        assert_eq!(to_html(r"{\p1}m 0 0 l 100 0"), "");
+    }
+
+    #[test]
+    fn reset_clears_every_style() {
+        assert_eq!(
+            to_html(r"{\b1\c&H0000FF&}red{\r}plain"),
+            "<b><span style=\"color:#ff0000\">red</span></b>plain"
+        );
+        assert_eq!(to_html(r"{\p1}m 0 0{\r}text"), "text");
+    }
+
+    #[test]
+    fn line_breaks_become_br() {
+        assert_eq!(to_html("a\\Nb\\nc\r\nd"), "a<br>b<br>c<br>d");
+    }
+
+    #[test]
+    fn comments_and_unknown_tags_are_ignored() {
+        assert_eq!(to_html(r"{note}{\blur2\bord3\pos(1,2)}text"), "text");
+    }
+}
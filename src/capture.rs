@@ -0,0 +1,473 @@
+use serde_json::Value;
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::ass;
+use crate::event_loop::{Subtitle, TimingOffsets};
+use crate::track_list;
+
+/// Properties observed to build subtitles. mpv doesn't promise to report the
+/// changes of one subtitle switch in observation order, so a line's text may
+/// arrive before its timings; see `SubtitleCapture`. Styled text is
+/// `sub-text/ass` on current mpv and `sub-text-ass` on older releases;
+/// whichever doesn't exist just stays null.
+pub const OBSERVED_PROPERTIES: [&str; 16] = [
+    "path",
+    "media-title",
+    "track-list",
+    "aid",
+    "sid",
+    "sub-delay",
+    "sub-speed",
+    "audio-delay",
+    "sub-start",
+    "sub-end",
+    "secondary-sub-start",
+    "secondary-sub-end",
+    "secondary-sub-text",
+    "sub-text/ass",
+    "sub-text-ass",
+    "sub-text",
+];
+
+/// How many recent secondary lines are kept around for pairing.
+const SECONDARY_HISTORY: usize = 8;
+
+/// Latest observed value of each property.
+#[derive(Default)]
+struct Snapshot {
+    sub_start: Option<f64>,
+    sub_end: Option<f64>,
+    media_path: Option<String>,
+    media_title: Option<String>,
+    aid: Option<i64>,
+    track_list: Value,
+    sid: Option<i64>,
+    offsets: TimingOffsets,
+    secondary_start: Option<f64>,
+    secondary_end: Option<f64>,
+    secondary_text: Option<String>,
+    ass_text: Option<String>,
+    legacy_ass_text: Option<String>,
+}
+
+struct SecondaryCue {
+    text: String,
+    start: Option<f64>,
+    end: Option<f64>,
+}
+
+/// What was reported since the last line was emitted.
+#[derive(Default)]
+struct Fresh {
+    start: bool,
+    end: bool,
+    ass: bool,
+}
+
+/// Builds subtitles from property changes. A line is emitted once its text,
+/// start, end and (if mpv provides it) styled text have all changed, in any
+/// order. mpv reports each changed property once per round of
+/// notifications, so when a property repeats before that happens, whatever
+/// didn't change kept its value and the line is emitted as it stands.
+#[derive(Default)]
+pub struct SubtitleCapture {
+    snapshot: Snapshot,
+    secondary_cues: VecDeque<SecondaryCue>,
+    /// Text of a line still waiting for the rest of its properties.
+    pending: Option<String>,
+    /// Properties reported since `pending` arrived.
+    reported: HashSet<String>,
+    fresh: Fresh,
+    /// Whether mpv provides styled text at all.
+    has_ass: bool,
+}
+
+impl SubtitleCapture {
+    /// Forgets all observed values, e.g. after the IPC connection dropped.
+    /// mpv re-sends every observed property once observation resumes.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Picks the secondary line that overlaps `[start, end]` the most. Falls
+    /// back to the secondary line currently on screen when either side has no
+    /// timings (e.g. mpv versions without `secondary-sub-start`).
+    fn translation_for(&self, start: Option<f64>, end: Option<f64>) -> Option<String> {
+        let on_screen = self.snapshot.secondary_text.clone();
+        let (Some(start), Some(end)) = (start, end) else {
+            return on_screen;
+        };
+        if self.snapshot.secondary_text.is_some() && self.snapshot.secondary_start.is_none() {
+            return on_screen;
+        }
+
+        self.secondary_cues
+            .iter()
+            .filter_map(|cue| {
+                let overlap = cue.end?.min(end) - cue.start?.max(start);
+                (overlap > 0.0).then_some((overlap, cue))
+            })
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, cue)| cue.text.clone())
+    }
+
+    /// Applies a property change and returns a subtitle once a new non-empty
+    /// line is complete.
+    pub fn on_property_change(
+        &mut self,
+        name: &str,
+        data: &Value,
+        next_id: &AtomicU64,
+    ) -> Option<Subtitle> {
+        // A repeat starts the next round; a new file won't complete the line.
+        let mut line = None;
+        if self.pending.is_some() && (self.reported.contains(name) || name == "path") {
+            line = self.finish(next_id);
+        }
+        self.apply(name, data);
+        if self.pending.is_some() {
+            self.reported.insert(name.to_string());
+            let fresh = &self.fresh;
+            if fresh.start && fresh.end && (fresh.ass || !self.has_ass) {
+                line = self.finish(next_id);
+            }
+        }
+        line
+    }
+
+    fn apply(&mut self, name: &str, data: &Value) {
+        let snapshot = &mut self.snapshot;
+        match name {
+            "sub-start" => {
+                snapshot.sub_start = data.as_f64();
+                self.fresh.start |= snapshot.sub_start.is_some();
+            }
+            "sub-end" => {
+                snapshot.sub_end = data.as_f64();
+                self.fresh.end |= snapshot.sub_end.is_some();
+            }
+            "path" => {
+                snapshot.media_path = data.as_str().map(|s| s.to_string());
+                self.secondary_cues.clear();
+            }
+            "media-title" => snapshot.media_title = data.as_str().map(|s| s.to_string()),
+            "track-list" => snapshot.track_list = data.clone(),
+            "aid" => snapshot.aid = data.as_i64(),
+            "sid" => snapshot.sid = data.as_i64(),
+            "sub-delay" => snapshot.offsets.sub_delay = data.as_f64().unwrap_or(0.0),
+            "sub-speed" => snapshot.offsets.sub_speed = data.as_f64().unwrap_or(1.0),
+            "audio-delay" => snapshot.offsets.audio_delay = data.as_f64().unwrap_or(0.0),
+            "secondary-sub-start" => snapshot.secondary_start = data.as_f64(),
+            "secondary-sub-end" => snapshot.secondary_end = data.as_f64(),
+            "secondary-sub-text" => {
+                snapshot.secondary_text = data
+                    .as_str()
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.to_string());
+                if let Some(text) = &snapshot.secondary_text {
+                    if self.secondary_cues.len() == SECONDARY_HISTORY {
+                        self.secondary_cues.pop_front();
+                    }
+                    self.secondary_cues.push_back(SecondaryCue {
+                        text: text.clone(),
+                        start: snapshot.secondary_start,
+                        end: snapshot.secondary_end,
+                    });
+                }
+            }
+            "sub-text/ass" | "sub-text-ass" => {
+                let text = data.as_str().map(|s| s.to_string());
+                let is_line = text.as_deref().is_some_and(|s| !s.is_empty());
+                self.has_ass |= text.is_some();
+                self.fresh.ass |= is_line;
+                if name == "sub-text/ass" {
+                    snapshot.ass_text = text;
+                } else {
+                    snapshot.legacy_ass_text = text;
+                }
+            }
+            "sub-text" => match data.as_str().filter(|s| !s.is_empty()) {
+                Some(text) => {
+                    self.pending = Some(text.to_string());
+                    self.reported.clear();
+                }
+                // Timings reported with the gap belong to no line.
+                None => self.fresh = Fresh::default(),
+            },
+            _ => {}
+        }
+    }
+
+    /// Emits the pending line with the current snapshot.
+    fn finish(&mut self, next_id: &AtomicU64) -> Option<Subtitle> {
+        let text = self.pending.take()?;
+        let fresh = std::mem::take(&mut self.fresh);
+        self.reported.clear();
+
+        let snapshot = &self.snapshot;
+        let html = fresh
+            .ass
+            .then(|| {
+                snapshot
+                    .ass_text
+                    .as_deref()
+                    .or(snapshot.legacy_ass_text.as_deref())
+            })
+            .flatten()
+            .map(ass::to_html)
+            .filter(|html| !html.is_empty());
+        let audio_source =
+            snapshot
+                .aid
+                .zip(snapshot.media_path.as_deref())
+                .and_then(|(aid, media_path)| {
+                    track_list::resolve_audio(&snapshot.track_list, aid, media_path)
+                });
+        Some(Subtitle {
+            id: next_id.fetch_add(1, Ordering::Relaxed),
+            text,
+            html,
+            sub_start: snapshot.sub_start,
+            sub_end: snapshot.sub_end,
+            media_path: snapshot.media_path.clone(),
+            media_title: snapshot.media_title.clone(),
+            aid: snapshot.aid,
+            audio_source,
+            sid: snapshot.sid,
+            translation: self.translation_for(snapshot.sub_start, snapshot.sub_end),
+            offsets: snapshot.offsets,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpv_client::MpvEvent;
+
+    /// Synthetic IPC output, written by hand in the shape mpv sends rather
+    /// than captured from a real player, covering two files: a line followed
+    /// immediately by the next one, a gap, a seek that lands mid-line, and a
+    /// file without audio.
+    const SYNTHETIC_STREAM: &str = r#"{"request_id":1,"error":"success"}
+{"event":"property-change","id":1,"name":"path","data":"/media/show/ep03.mkv"}
+{"request_id":2,"error":"success"}
+{"event":"property-change","id":2,"name":"aid","data":1}
+{"request_id":3,"error":"success"}
+{"event":"property-change","id":3,"name":"sub-start"}
+{"request_id":4,"error":"success"}
+{"event":"property-change","id":4,"name":"sub-end"}
+{"request_id":5,"error":"success"}
+{"event":"property-change","id":5,"name":"sub-text","data":""}
+{"event":"property-change","id":3,"name":"sub-start","data":12.345}
+{"event":"property-change","id":4,"name":"sub-end","data":14.1}
+{"event":"property-change","id":5,"name":"sub-text","data":"諦めるな"}
+{"event":"property-change","id":3,"name":"sub-start","data":14.1}
+{"event":"property-change","id":4,"name":"sub-end","data":15.9}
+{"event":"property-change","id":5,"name":"sub-text","data":"まだ終わってない"}
+{"event":"property-change","id":3,"name":"sub-start"}
+{"event":"property-change","id":4,"name":"sub-end"}
+{"event":"property-change","id":5,"name":"sub-text","data":""}
+{"event":"seek"}
+{"event":"property-change","id":3,"name":"sub-start","data":301.5}
+{"event":"property-change","id":4,"name":"sub-end","data":303.25}
+{"event":"property-change","id":5,"name":"sub-text","data":"行くぞ"}
+{"event":"playback-restart"}
+{"event":"end-file","reason":"stop","playlist_entry_id":1}
+{"event":"property-change","id":1,"name":"path","data":"/media/show/ep04.mkv"}
+{"event":"property-change","id":2,"name":"aid","data":false}
+{"event":"property-change","id":3,"name":"sub-start","data":1.0}
+{"event":"property-change","id":4,"name":"sub-end","data":2.5}
+{"event":"property-change","id":5,"name":"sub-text","data":"次回予告"}
+"#;
+
+    fn replay(stream: &str) -> Vec<Subtitle> {
+        let next_id = AtomicU64::new(1);
+        let mut capture = SubtitleCapture::default();
+        stream
+            .lines()
+            .filter_map(|line| MpvEvent::parse(&serde_json::from_str(line).unwrap()))
+            .filter_map(|event| match event {
+                MpvEvent::PropertyChange { name, data } => {
+                    capture.on_property_change(&name, &data, &next_id)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replayed_stream_yields_timings_of_each_line() {
+        let subs = replay(SYNTHETIC_STREAM);
+        let captured: Vec<_> = subs
+            .iter()
+            .map(|s| (s.id, s.text.as_str(), s.sub_start, s.sub_end))
+            .collect();
+
+        assert_eq!(
+            captured,
+            [
+                (1, "諦めるな", Some(12.345), Some(14.1)),
+                (2, "まだ終わってない", Some(14.1), Some(15.9)),
+                (3, "行くぞ", Some(301.5), Some(303.25)),
+                (4, "次回予告", Some(1.0), Some(2.5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn replayed_stream_tracks_file_and_audio() {
+        let subs = replay(SYNTHETIC_STREAM);
+
+        assert_eq!(subs[2].media_path.as_deref(), Some("/media/show/ep03.mkv"));
+        assert_eq!(subs[2].aid, Some(1));
+        assert_eq!(subs[3].media_path.as_deref(), Some("/media/show/ep04.mkv"));
+        assert_eq!(subs[3].aid, None);
+    }
+
+    #[test]
+    fn replayed_stream_pairs_overlapping_secondary_line() {
+        let stream = r#"{"event":"property-change","id":1,"name":"path","data":"/media/film.mkv"}
+{"event":"property-change","id":5,"name":"secondary-sub-start","data":9.8}
+{"event":"property-change","id":6,"name":"secondary-sub-end","data":13.0}
+{"event":"property-change","id":7,"name":"secondary-sub-text","data":"Don't give up"}
+{"event":"property-change","id":3,"name":"sub-start","data":10.0}
+{"event":"property-change","id":4,"name":"sub-end","data":12.0}
+{"event":"property-change","id":8,"name":"sub-text","data":"諦めるな"}
+{"event":"property-change","id":5,"name":"secondary-sub-start"}
+{"event":"property-change","id":6,"name":"secondary-sub-end"}
+{"event":"property-change","id":7,"name":"secondary-sub-text","data":""}
+{"event":"property-change","id":3,"name":"sub-start","data":20.0}
+{"event":"property-change","id":4,"name":"sub-end","data":21.0}
+{"event":"property-change","id":8,"name":"sub-text","data":"行くぞ"}
+"#;
+        let subs = replay(stream);
+
+        assert_eq!(subs[0].translation.as_deref(), Some("Don't give up"));
+        assert_eq!(subs[1].translation, None);
+    }
+
+    #[test]
+    fn replayed_stream_maps_timings_through_delays() {
+        let stream = r#"{"event":"property-change","id":1,"name":"path","data":"/media/film.mkv"}
+{"event":"property-change","id":5,"name":"sub-delay","data":1.5}
+{"event":"property-change","id":6,"name":"sub-speed","data":2.0}
+{"event":"property-change","id":7,"name":"audio-delay","data":0.25}
+{"event":"property-change","id":3,"name":"sub-start","data":10.0}
+{"event":"property-change","id":4,"name":"sub-end","data":12.0}
+{"event":"property-change","id":8,"name":"sub-text","data":"諦めるな"}
+"#;
+        let subs = replay(stream);
+
+        assert_eq!(subs[0].timing(), Some((10.0, 12.0)));
+        assert_eq!(subs[0].media_timing(), Some((21.5, 25.5)));
+        assert_eq!(subs[0].audio_timing(), Some((21.25, 25.25)));
+    }
+
+    #[test]
+    fn replayed_stream_resolves_external_audio_track() {
+        let stream = r#"{"event":"property-change","id":1,"name":"path","data":"/media/film.mkv"}
+{"event":"property-change","id":2,"name":"track-list","data":[{"id":1,"type":"audio","external":false,"ff-index":1},{"id":2,"type":"audio","external":true,"external-filename":"/media/film.ja.mka","ff-index":0}]}
+{"event":"property-change","id":3,"name":"aid","data":2}
+{"event":"property-change","id":4,"name":"sub-start","data":10.0}
+{"event":"property-change","id":5,"name":"sub-end","data":12.0}
+{"event":"property-change","id":6,"name":"sub-text","data":"諦めるな"}
+{"event":"property-change","id":3,"name":"aid","data":1}
+{"event":"property-change","id":4,"name":"sub-start","data":20.0}
+{"event":"property-change","id":5,"name":"sub-end","data":21.0}
+{"event":"property-change","id":6,"name":"sub-text","data":"行くぞ"}
+"#;
+        let subs = replay(stream);
+        let inputs: Vec<_> = subs
+            .iter()
+            .map(|s| s.audio_input().map(|a| (a.input_path, a.stream)))
+            .collect();
+
+        assert_eq!(
+            inputs,
+            [
+                Some(("/media/film.ja.mka".to_string(), "0:0".to_string())),
+                Some(("/media/film.mkv".to_string(), "0:1".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn replayed_stream_waits_for_timings_reported_after_the_text() {
+        let stream = r#"{"event":"property-change","id":1,"name":"path","data":"/media/film.mkv"}
+{"event":"property-change","id":2,"name":"sub-start","data":10.0}
+{"event":"property-change","id":3,"name":"sub-end","data":12.0}
+{"event":"property-change","id":4,"name":"sub-text","data":"諦めるな"}
+{"event":"property-change","id":5,"name":"time-pos","data":12.02}
+{"event":"property-change","id":4,"name":"sub-text","data":"行くぞ"}
+{"event":"property-change","id":5,"name":"time-pos","data":12.54}
+{"event":"property-change","id":2,"name":"sub-start","data":12.5}
+{"event":"property-change","id":3,"name":"sub-end","data":14.0}
+{"event":"property-change","id":4,"name":"sub-text","data":""}
+{"event":"property-change","id":2,"name":"sub-start"}
+{"event":"property-change","id":3,"name":"sub-end"}
+{"event":"property-change","id":4,"name":"sub-text","data":"まだだ"}
+{"event":"property-change","id":5,"name":"time-pos","data":20.0}
+{"event":"property-change","id":2,"name":"sub-start","data":20.0}
+{"event":"property-change","id":3,"name":"sub-end","data":21.0}
+"#;
+        let subs = replay(stream);
+        let captured: Vec<_> = subs
+            .iter()
+            .map(|s| (s.text.as_str(), s.sub_start, s.sub_end))
+            .collect();
+
+        assert_eq!(
+            captured,
+            [
+                ("諦めるな", Some(10.0), Some(12.0)),
+                ("行くぞ", Some(12.5), Some(14.0)),
+                ("まだだ", Some(20.0), Some(21.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn replayed_stream_emits_unchanged_timings_after_a_round() {
+        let stream = r#"{"event":"property-change","id":1,"name":"path","data":"/media/film.mkv"}
+{"event":"property-change","id":2,"name":"sub-start","data":10.0}
+{"event":"property-change","id":3,"name":"sub-end","data":12.0}
+{"event":"property-change","id":4,"name":"sub-text","data":"諦めるな"}
+{"event":"property-change","id":5,"name":"time-pos","data":11.0}
+{"event":"property-change","id":4,"name":"sub-text","data":"諦めるな！"}
+{"event":"property-change","id":5,"name":"time-pos","data":11.04}
+{"event":"property-change","id":5,"name":"time-pos","data":11.08}
+{"event":"property-change","id":4,"name":"sub-text","data":"行くぞ"}
+{"event":"property-change","id":1,"name":"path","data":"/media/next.mkv"}
+"#;
+        let subs = replay(stream);
+        let captured: Vec<_> = subs
+            .iter()
+            .map(|s| (s.text.as_str(), s.sub_start, s.media_path.as_deref()))
+            .collect();
+
+        assert_eq!(
+            captured,
+            [
+                ("諦めるな", Some(10.0), Some("/media/film.mkv")),
+                ("諦めるな！", Some(10.0), Some("/media/film.mkv")),
+                ("行くぞ", Some(10.0), Some("/media/film.mkv")),
+            ]
+        );
+    }
+
+    #[test]
+    fn replayed_stream_waits_for_styled_text() {
+        let stream = r#"{"event":"property-change","id":1,"name":"sub-text/ass","data":""}
+{"event":"property-change","id":2,"name":"sub-text","data":"諦めるな"}
+{"event":"property-change","id":3,"name":"sub-start","data":10.0}
+{"event":"property-change","id":4,"name":"sub-end","data":12.0}
+{"event":"property-change","id":1,"name":"sub-text/ass","data":"{\\i1}諦めるな"}
+"#;
+        let subs = replay(stream);
+
+        assert_eq!(subs.len(), 1);
+        assert_eq!(subs[0].html.as_deref(), Some("<i>諦めるな</i>"));
+    }
+}
@@ -1,173 +1,270 @@
+use base64::Engine;
 use futures_util::{SinkExt, StreamExt};
-use log::{debug, error, info, warn};
+use log::{debug, info, warn};
 use serde::Deserialize;
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, hash_map};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{RwLock, broadcast};
-use tokio::time::{Duration, timeout};
+use tokio::sync::{RwLock, Semaphore, broadcast, watch};
+use tokio::task::{AbortHandle, JoinSet};
 use tokio_tungstenite::{accept_async, tungstenite::Message};
 
-use crate::media::FfmpegRequest;
+use crate::capture::{OBSERVED_PROPERTIES, SubtitleCapture};
+use crate::export::{self, ExportFormat};
+use crate::history::History;
+use crate::media::{AudioConfig, FfmpegError, FfmpegRequest, ImageConfig};
+use crate::media_cache::{CacheKey, MediaCache};
+use crate::mpv_client::{MpvClient, MpvError, MpvEvent};
 use crate::mpv_stream::MpvStream;
+use crate::playback::{PLAYBACK_PROPERTIES, PLAYBACK_STATE_INTERVAL, PlaybackState, Position};
+use crate::search::{SearchMode, SearchQuery};
+use crate::store::{RetentionPolicy, SubtitleStore};
+use crate::study::{StudySettings, StudyTracker};
+use crate::subtitle_track;
+use crate::track_list::{self, TrackSource};
 
 #[derive(Clone)]
 pub struct Subtitle {
     pub id: u64,
     pub text: String,
-    pub sub_start: f64,
-    pub sub_end: f64,
-    pub media_path: String,
-    pub aid: i64,
+    /// `text` with its ASS styling converted to HTML, when mpv provides it.
+    pub html: Option<String>,
+    pub sub_start: Option<f64>,
+    pub sub_end: Option<f64>,
+    pub media_path: Option<String>,
+    pub media_title: Option<String>,
+    /// `None` when mpv has no audio track selected (`aid` = false).
+    pub aid: Option<i64>,
+    /// File and stream of `aid`, resolved through mpv's `track-list`.
+    pub audio_source: Option<TrackSource>,
+    pub sid: Option<i64>,
+    /// Overlapping line from the secondary subtitle track, if one is shown.
+    pub translation: Option<String>,
+    pub offsets: TimingOffsets,
 }
 
-struct SharedState {
-    subtitles: RwLock<HashMap<u64, Subtitle>>,
-}
+impl Subtitle {
+    /// `sub_start`/`sub_end` as reported by mpv, in subtitle time.
+    pub fn timing(&self) -> Option<(f64, f64)> {
+        Some((self.sub_start?, self.sub_end?))
+    }
 
-impl SharedState {
-    fn new() -> Arc<Self> {
-        Arc::new(Self {
-            subtitles: RwLock::new(HashMap::new()),
-        })
+    /// When the line is shown on the playback timeline, i.e. where to seek
+    /// to or grab a video frame.
+    pub fn media_timing(&self) -> Option<(f64, f64)> {
+        let (start, end) = self.timing()?;
+        Some((self.offsets.to_media(start), self.offsets.to_media(end)))
+    }
+
+    /// Where ffmpeg reads the line's audio from. Falls back to the `aid`-th
+    /// audio stream of the media file when the track couldn't be resolved.
+    pub fn audio_input(&self) -> Option<TrackSource> {
+        let aid = self.aid?;
+        self.audio_source
+            .clone()
+            .or_else(|| Some(TrackSource::nth_audio(self.media_path.as_deref()?, aid)))
+    }
+
+    /// Where in the audio stream the audio heard during the line is.
+    pub fn audio_timing(&self) -> Option<(f64, f64)> {
+        let (start, end) = self.media_timing()?;
+        let delay = self.offsets.audio_delay;
+        Some((start - delay, end - delay))
     }
 }
 
-struct PendingSubtitle {
-    id: u64,
-    text: String,
-    responses: [Option<serde_json::Value>; 4], // sub_start, sub_end, path, aid
+/// mpv's `sub-delay`, `sub-speed` and `audio-delay` when a line was
+/// captured. mpv shows a subtitle timed `t` at `t * sub_speed + sub_delay`
+/// and plays audio `audio_delay` seconds late.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimingOffsets {
+    pub sub_delay: f64,
+    pub sub_speed: f64,
+    pub audio_delay: f64,
 }
 
-impl PendingSubtitle {
-    fn new(id: u64, text: String) -> Self {
+impl Default for TimingOffsets {
+    fn default() -> Self {
         Self {
-            id,
-            text,
-            responses: Default::default(),
+            sub_delay: 0.0,
+            sub_speed: 1.0,
+            audio_delay: 0.0,
         }
     }
+}
 
-    fn set_response(&mut self, index: usize, value: serde_json::Value) {
-        if index < 4 {
-            self.responses[index] = Some(value);
-        }
+impl TimingOffsets {
+    pub fn to_media(self, t: f64) -> f64 {
+        t * self.sub_speed + self.sub_delay
     }
+}
 
-    fn is_complete(&self) -> bool {
-        self.responses.iter().all(|r| r.is_some())
-    }
+#[derive(Clone, Copy)]
+pub enum MpvStatus {
+    Disconnected,
+    Reconnected,
+}
 
-    fn into_subtitle(self) -> Subtitle {
-        Subtitle {
-            id: self.id,
-            text: self.text,
-            sub_start: self.responses[0].as_ref().unwrap().as_f64().unwrap(),
-            sub_end: self.responses[1].as_ref().unwrap().as_f64().unwrap(),
-            media_path: self.responses[2]
-                .as_ref()
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string(),
-            aid: self.responses[3].as_ref().unwrap().as_i64().unwrap(),
+impl MpvStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            MpvStatus::Disconnected => "disconnected",
+            MpvStatus::Reconnected => "reconnected",
         }
     }
 }
 
-async fn query_mpv_property(
-    mpv: &mut MpvStream,
-    property: &str,
-    request_id: u64,
-) -> std::io::Result<serde_json::Value> {
-    let cmd = format!(
-        "{{\"command\":[\"get_property\",\"{}\"],\"request_id\":{}}}\n",
-        property, request_id
-    );
-    mpv.write_all(cmd.as_bytes()).await?;
+/// Everything broadcast from the mpv side to connected WebSocket clients.
+#[derive(Clone)]
+pub enum ServerEvent {
+    Subtitle(Subtitle),
+    MpvStatus(MpvStatus),
+    PlaybackState(PlaybackState),
+    TrackLoaded { media_path: String, count: usize },
+}
 
-    let mut line = String::new();
-    loop {
-        line.clear();
-        if mpv.read_line(&mut line).await? == 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "mpv IPC closed while waiting for property response",
-            ));
-        }
-        if let Ok(json) = serde_json::from_str::<serde_json::Value>(&line)
-            && json.get("request_id").and_then(|v| v.as_u64()) == Some(request_id)
-        {
-            return Ok(json);
+/// Cues extracted from the active subtitle track of the current file. Kept
+/// apart from captured lines so a whole track doesn't count against, or
+/// evict, captures. Ids come from the same counter, so media requests can
+/// resolve either.
+#[derive(Default)]
+struct SubtitleTrack {
+    media_path: Option<String>,
+    cues: BTreeMap<u64, Subtitle>,
+}
+
+impl SubtitleTrack {
+    /// Cues of `media_path` shown during `[start, end]` of media time, in id
+    /// order.
+    fn range(&self, media_path: &str, start: f64, end: f64) -> Vec<&Subtitle> {
+        if self.media_path.as_deref() != Some(media_path) {
+            return Vec::new();
         }
+        self.cues
+            .values()
+            .filter(|s| {
+                s.media_timing()
+                    .is_some_and(|(sub_start, sub_end)| sub_start <= end && sub_end >= start)
+            })
+            .collect()
     }
 }
 
-async fn query_mpv_property_with_timeout(
-    mpv: &mut MpvStream,
-    property: &str,
-    request_id: u64,
-) -> std::io::Result<serde_json::Value> {
-    timeout(
-        Duration::from_secs(1),
-        query_mpv_property(mpv, property, request_id),
-    )
-    .await
-    .map_err(|_| {
-        std::io::Error::new(
-            std::io::ErrorKind::TimedOut,
-            format!("Timed out querying mpv property '{}'", property),
-        )
-    })?
+/// Bumped whenever a message or request changes incompatibly.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Default number of subtitles replayed to a client that (re)connects.
+const BACKLOG_LIMIT: usize = 200;
+
+struct SharedState {
+    subtitles: RwLock<SubtitleStore>,
+    track: RwLock<SubtitleTrack>,
+    /// File mpv is currently playing, as last observed.
+    media_path: RwLock<Option<String>>,
+    /// Last `playback_state` broadcast, for newly connected clients.
+    playback: RwLock<PlaybackState>,
+    // Lives here rather than in `handle_mpv` so ids stay unique across
+    // reconnects.
+    next_subtitle_id: AtomicU64,
+    history: Option<Arc<History>>,
+    /// Used by clients for playback control.
+    mpv: MpvClient,
+    /// Bumped by every playback command so a running `replay` knows it was
+    /// superseded.
+    playback_generation: AtomicU64,
+    position: watch::Sender<Position>,
+    study: watch::Sender<StudySettings>,
+    /// One permit per ffmpeg process allowed to run at once.
+    ffmpeg_jobs: Semaphore,
+    ffmpeg_timeout: Duration,
+    media_cache: Mutex<MediaCache>,
+    /// Results being prefetched, so clients seeing the same new line don't
+    /// each start the same ffmpeg run. The channel closes when the prefetch
+    /// ends, for requests waiting on its result.
+    prefetching: Mutex<HashMap<CacheKey, watch::Receiver<()>>>,
 }
 
-async fn get_mpv_pid(mpv: &mut MpvStream) -> std::io::Result<u32> {
-    let json = match query_mpv_property_with_timeout(mpv, "pid", 1).await {
-        Ok(json) => json,
-        Err(_) => query_mpv_property_with_timeout(mpv, "process-id", 2).await?,
-    };
-    let status = json.get("error").and_then(|e| e.as_str()).unwrap_or("");
-    if status != "success" {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("mpv returned error querying PID: {}", status),
-        ));
+impl SharedState {
+    fn new(config: ServerConfig, mpv: MpvClient) -> Arc<Self> {
+        Arc::new(Self {
+            subtitles: RwLock::new(SubtitleStore::new(config.retention)),
+            track: RwLock::new(SubtitleTrack::default()),
+            media_path: RwLock::new(None),
+            playback: RwLock::new(PlaybackState::default()),
+            next_subtitle_id: AtomicU64::new(1),
+            history: config.history.map(Arc::new),
+            mpv,
+            playback_generation: AtomicU64::new(0),
+            position: watch::Sender::new(Position::default()),
+            study: watch::Sender::new(StudySettings::default()),
+            ffmpeg_jobs: Semaphore::new(config.max_ffmpeg_jobs.max(1)),
+            ffmpeg_timeout: config.ffmpeg_timeout,
+            media_cache: Mutex::new(MediaCache::new(config.media_cache_size)),
+            prefetching: Mutex::new(HashMap::new()),
+        })
     }
 
-    let pid = json
-        .get("data")
-        .and_then(|d| {
-            d.as_u64()
-                .or_else(|| d.as_i64().and_then(|n| u64::try_from(n).ok()))
-        })
-        .ok_or_else(|| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "mpv returned non-integer PID",
-            )
-        })?;
+    /// Captured line or track cue `id`.
+    async fn subtitle(&self, id: u64) -> Result<Subtitle, RequestError> {
+        if let Some(sub) = self.subtitles.read().await.get(id) {
+            return Ok(sub.clone());
+        }
+        self.track
+            .read()
+            .await
+            .cues
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| RequestError::unknown_subtitle(id))
+    }
+}
 
-    u32::try_from(pid)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "mpv PID out of range"))
+async fn get_mpv_pid(mpv: &MpvClient) -> Result<u32, MpvError> {
+    match mpv.get_property::<u32>("pid").await {
+        Ok(pid) => Ok(pid),
+        Err(_) => mpv.get_property::<u32>("process-id").await,
+    }
+}
+
+async fn verify_mpv_pid(mpv: &MpvClient, expected: u32, socket_path: &str) -> std::io::Result<()> {
+    let actual = get_mpv_pid(mpv)
+        .await
+        .map_err(|e| std::io::Error::other(format!("mpv returned error querying PID: {}", e)))?;
+    if actual != expected {
+        return Err(std::io::Error::other(format!(
+            "MPV_IPC_PID_MISMATCH expected={} actual={} socket={}",
+            expected, actual, socket_path
+        )));
+    }
+    Ok(())
+}
+
+pub struct ServerConfig {
+    /// Slots in the broadcast channel feeding WebSocket clients. A client
+    /// that falls further behind is resynced from the subtitle store.
+    pub broadcast_capacity: usize,
+    pub retention: RetentionPolicy,
+    /// Database every captured subtitle is recorded to, if enabled.
+    pub history: Option<History>,
+    /// Longest a single ffmpeg run may take before it is killed.
+    pub ffmpeg_timeout: Duration,
+    /// ffmpeg processes allowed to run at once; further requests wait.
+    pub max_ffmpeg_jobs: usize,
+    /// Bytes of generated media kept for repeated requests.
+    pub media_cache_size: usize,
 }
 
 pub async fn run_server(
     socket_path: &str,
     port: u16,
     expected_mpv_pid: Option<u32>,
+    config: ServerConfig,
 ) -> std::io::Result<()> {
-    let mut mpv = MpvStream::connect(socket_path).await?;
+    let mpv = MpvClient::new(MpvStream::connect(socket_path).await?);
     if let Some(expected) = expected_mpv_pid {
-        let actual = get_mpv_pid(&mut mpv).await?;
-        if actual != expected {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!(
-                    "MPV_IPC_PID_MISMATCH expected={} actual={} socket={}",
-                    expected, actual, socket_path
-                ),
-            ));
-        }
+        verify_mpv_pid(&mpv, expected, socket_path).await?;
     }
     let listener = TcpListener::bind(("0.0.0.0", port)).await?;
 
@@ -177,18 +274,21 @@ pub async fn run_server(
             .local_addr()
             .map_or_else(|_| format!("port {}", port), |a| a.to_string())
     );
+    // Probe ffmpeg now that the port is up, rather than on the first hello.
+    tokio::spawn(crate::media::capabilities());
 
-    let state = SharedState::new();
-    let (subtitle_tx, _) = broadcast::channel::<Subtitle>(64);
+    let (event_tx, _) = broadcast::channel::<ServerEvent>(config.broadcast_capacity.max(1));
+    let state = SharedState::new(config, mpv.clone());
 
     let mpv_state = state.clone();
-    let mpv_tx = subtitle_tx.clone();
+    let mpv_tx = event_tx.clone();
+    let mpv_socket_path = socket_path.to_string();
     tokio::spawn(async move {
-        if let Err(e) = handle_mpv(mpv, mpv_state, mpv_tx).await {
-            error!("MPV handler error: {}", e);
+        if let Err(e) = handle_mpv(mpv, mpv_state, mpv_tx, expected_mpv_pid, &mpv_socket_path).await
+        {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
         }
-        info!("MPV connection closed, shutting down.");
-        std::process::exit(0);
     });
 
     let mut client_id = 0u64;
@@ -198,7 +298,7 @@ pub async fn run_server(
         let id = client_id;
 
         let client_state = state.clone();
-        let client_rx = subtitle_tx.subscribe();
+        let client_rx = event_tx.subscribe();
 
         tokio::spawn(async move {
             info!("[client:{}] Connected from {}", id, addr);
@@ -212,128 +312,466 @@ pub async fn run_server(
 }
 
 async fn handle_mpv(
-    mut mpv: MpvStream,
+    mpv: MpvClient,
     state: Arc<SharedState>,
-    tx: broadcast::Sender<Subtitle>,
+    tx: broadcast::Sender<ServerEvent>,
+    expected_mpv_pid: Option<u32>,
+    socket_path: &str,
 ) -> std::io::Result<()> {
-    mpv.write_all(b"{\"command\":[\"observe_property\",1,\"sub-text\"]}\n")
-        .await?;
+    let mut events = mpv.subscribe();
+    for property in OBSERVED_PROPERTIES.into_iter().chain(PLAYBACK_PROPERTIES) {
+        if let Err(e) = mpv.observe(property).await {
+            warn!("[mpv] Failed to observe '{}': {}", property, e);
+        }
+    }
     info!("Connected to mpv, observing subtitle changes");
+    let mut track_load = None;
+    reload_subtitle_track(&mut track_load, &mpv, &state, &tx);
 
-    let mut pending: HashMap<u64, PendingSubtitle> = HashMap::new();
-    let mut next_subtitle_id = 1u64;
-    let mut next_request_id = 10u64;
-    let mut line = String::new();
+    let mut capture = SubtitleCapture::default();
+    let mut study = StudyTracker::default();
+    let mut playback = PlaybackState::default();
+    let mut playback_changed = false;
+    let mut playback_tick = tokio::time::interval(PLAYBACK_STATE_INTERVAL);
+    playback_tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
     loop {
-        line.clear();
-        if mpv.read_line(&mut line).await? == 0 {
-            return Ok(()); // EOF
-        }
-
-        let Ok(json) = serde_json::from_str::<serde_json::Value>(&line) else {
-            continue;
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = playback_tick.tick(), if playback_changed => {
+                playback_changed = false;
+                *state.playback.write().await = playback.clone();
+                let _ = tx.send(ServerEvent::PlaybackState(playback.clone()));
+                continue;
+            }
         };
 
-        // Handle property responses (request_id encodes: base_id + property_index)
-        if let Some(request_id) = json.get("request_id").and_then(|r| r.as_u64()) {
-            let base_id = request_id / 10 * 10; // Round down to base
-            let prop_idx = (request_id % 10) as usize;
-
-            if let Some(p) = pending.get_mut(&base_id)
-                && let Some(data) = json.get("data").cloned()
-            {
-                p.set_response(prop_idx, data);
+        match event {
+            Ok(MpvEvent::PropertyChange { name, data }) => {
+                if name == "path" {
+                    *state.media_path.write().await = data.as_str().map(|s| s.to_string());
+                }
+                playback_changed |= playback.apply(&name, &data);
+                state.position.send_if_modified(|position| {
+                    let changed = *position != playback.position();
+                    *position = playback.position();
+                    changed
+                });
+                if let Some(sub) = capture.on_property_change(&name, &data, &state.next_subtitle_id)
+                {
+                    info!("[sub:{}] {}", sub.id, sub.text);
+                    study.on_subtitle(&sub, &state.study.borrow());
+                    publish_subtitle(&state, &tx, sub).await;
+                }
+                if name == "path" {
+                    study.reset();
+                }
+                if name == "time-pos"
+                    && let Some(pos) = data.as_f64()
+                    && study.on_time_pos(pos)
+                    && state.study.borrow().enabled
+                {
+                    debug!("[study] Pausing at {:.3}", pos);
+                    if let Err(e) = mpv.set_property("pause", true).await {
+                        warn!("[study] Failed to pause: {}", e);
+                    }
+                }
             }
-
-            // Try to complete pending subtitles
-            let completed: Vec<_> = pending
-                .iter()
-                .filter(|(_, p)| p.is_complete())
-                .map(|(id, _)| *id)
-                .collect();
-
-            for base_id in completed {
-                let sub = pending.remove(&base_id).unwrap().into_subtitle();
-                debug!("[sub:{}] Broadcasting", sub.id);
-                state.subtitles.write().await.insert(sub.id, sub.clone());
-                let _ = tx.send(sub);
+            Ok(MpvEvent::Event { name, .. }) if name == "file-loaded" => {
+                reload_subtitle_track(&mut track_load, &mpv, &state, &tx);
             }
-            continue;
+            Ok(MpvEvent::Event { name, data }) => {
+                debug!("[mpv] {} event: {}", name, data);
+            }
+            Ok(MpvEvent::Disconnected) => {
+                capture.reset();
+                study.reset();
+                playback = PlaybackState::default();
+                playback_changed = true;
+                state.position.send_replace(Position::default());
+                let _ = tx.send(ServerEvent::MpvStatus(MpvStatus::Disconnected));
+            }
+            Ok(MpvEvent::Reconnected) => {
+                if let Some(expected) = expected_mpv_pid {
+                    verify_mpv_pid(&mpv, expected, socket_path).await?;
+                }
+                reload_subtitle_track(&mut track_load, &mpv, &state, &tx);
+                let _ = tx.send(ServerEvent::MpvStatus(MpvStatus::Reconnected));
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                warn!("[mpv] Event handler lagged, skipped {} events", n);
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
         }
+    }
+}
 
-        // Handle subtitle property changes
-        if json.get("event") == Some(&serde_json::json!("property-change"))
-            && let Some(text) = json
-                .get("data")
-                .and_then(|d| d.as_str())
-                .filter(|s| !s.is_empty())
-        {
-            let subtitle_id = next_subtitle_id;
-            next_subtitle_id += 1;
-
-            let base_id = next_request_id;
-            next_request_id += 10;
-
-            // Query all properties we need
-            let cmd = format!(
-                concat!(
-                    "{{\"command\":[\"get_property\",\"sub-start\"],\"request_id\":{0}}}\n",
-                    "{{\"command\":[\"get_property\",\"sub-end\"],\"request_id\":{1}}}\n",
-                    "{{\"command\":[\"get_property\",\"path\"],\"request_id\":{2}}}\n",
-                    "{{\"command\":[\"get_property\",\"aid\"],\"request_id\":{3}}}\n"
-                ),
-                base_id,
-                base_id + 1,
-                base_id + 2,
-                base_id + 3
+/// Starts loading the current subtitle track, aborting a load that is still
+/// running so its result for the previous file can't land last. Aborting
+/// also kills its ffmpeg.
+fn reload_subtitle_track(
+    track_load: &mut Option<AbortHandle>,
+    mpv: &MpvClient,
+    state: &Arc<SharedState>,
+    tx: &broadcast::Sender<ServerEvent>,
+) {
+    if let Some(previous) = track_load.take() {
+        previous.abort();
+    }
+    let load = tokio::spawn(load_subtitle_track(mpv.clone(), state.clone(), tx.clone()));
+    *track_load = Some(load.abort_handle());
+}
+
+/// Extracts the active subtitle track of the current file and replaces the
+/// previously loaded one.
+async fn load_subtitle_track(
+    mpv: MpvClient,
+    state: Arc<SharedState>,
+    tx: broadcast::Sender<ServerEvent>,
+) {
+    // Drop the previous file's cues first, so a file without a usable track
+    // doesn't keep serving them.
+    let media_path = mpv.get_property::<String>("path").await.ok();
+    *state.track.write().await = SubtitleTrack {
+        media_path: media_path.clone(),
+        cues: BTreeMap::new(),
+    };
+    let Some(media_path) = media_path else {
+        return;
+    };
+    let Ok(sid) = mpv.get_property::<i64>("sid").await else {
+        debug!("[track] No subtitle track selected");
+        return;
+    };
+    let Ok(track_list) = mpv.get_property::<serde_json::Value>("track-list").await else {
+        return;
+    };
+    let aid = mpv.get_property::<i64>("aid").await.ok();
+    let media_title = mpv.get_property::<String>("media-title").await.ok();
+    let defaults = TimingOffsets::default();
+    let offsets = TimingOffsets {
+        sub_delay: mpv
+            .get_property("sub-delay")
+            .await
+            .unwrap_or(defaults.sub_delay),
+        sub_speed: mpv
+            .get_property("sub-speed")
+            .await
+            .unwrap_or(defaults.sub_speed),
+        audio_delay: mpv
+            .get_property("audio-delay")
+            .await
+            .unwrap_or(defaults.audio_delay),
+    };
+
+    let audio_source = aid.and_then(|aid| track_list::resolve_audio(&track_list, aid, &media_path));
+    let Some(source) = track_list::resolve_subtitle(&track_list, sid, &media_path) else {
+        return;
+    };
+    let input_path = source.input_path.clone();
+    let req = FfmpegRequest::subtitle_track(&source.input_path, &source.stream);
+    let srt = match run_ffmpeg(&state, req).await {
+        Ok(srt) => srt,
+        Err(e) => {
+            warn!(
+                "[track] Failed to extract subtitle track from {}: {}",
+                input_path, e.message
             );
+            return;
+        }
+    };
+
+    let subs: Vec<_> = subtitle_track::parse_srt(&String::from_utf8_lossy(&srt))
+        .into_iter()
+        .map(|cue| Subtitle {
+            id: state.next_subtitle_id.fetch_add(1, Ordering::Relaxed),
+            text: cue.text,
+            html: None,
+            sub_start: Some(cue.start),
+            sub_end: Some(cue.end),
+            media_path: Some(media_path.clone()),
+            media_title: media_title.clone(),
+            aid,
+            audio_source: audio_source.clone(),
+            sid: Some(sid),
+            translation: None,
+            offsets,
+        })
+        .collect();
+    let count = subs.len();
+
+    *state.track.write().await = SubtitleTrack {
+        media_path: Some(media_path.clone()),
+        cues: subs.into_iter().map(|s| (s.id, s)).collect(),
+    };
+
+    info!("[track] Loaded {} cues from {}", count, input_path);
+    let _ = tx.send(ServerEvent::TrackLoaded { media_path, count });
+}
+
+async fn publish_subtitle(state: &SharedState, tx: &broadcast::Sender<ServerEvent>, sub: Subtitle) {
+    debug!("[sub:{}] Broadcasting", sub.id);
+    state.subtitles.write().await.insert(sub.clone());
 
-            mpv.write_all(cmd.as_bytes()).await?;
-            pending.insert(base_id, PendingSubtitle::new(subtitle_id, text.to_string()));
-            info!("[sub:{}] {}", subtitle_id, text);
+    if let Some(history) = state.history.clone() {
+        let record = sub.clone();
+        // Awaited so lines are written in capture order.
+        match tokio::task::spawn_blocking(move || history.record(&record)).await {
+            Ok(Err(e)) => warn!("[history] Failed to record subtitle {}: {}", sub.id, e),
+            Err(e) => warn!("[history] Failed to record subtitle {}: {}", sub.id, e),
+            Ok(Ok(_)) => {}
         }
     }
+
+    let _ = tx.send(ServerEvent::Subtitle(sub));
 }
 
 async fn handle_client(
     stream: TcpStream,
     id: u64,
     state: Arc<SharedState>,
-    mut subtitle_rx: broadcast::Receiver<Subtitle>,
+    mut event_rx: broadcast::Receiver<ServerEvent>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let ws = accept_async(stream).await?;
     let (mut ws_tx, mut ws_rx) = ws.split();
 
+    ws_tx
+        .send(Message::Text(hello_json().await.to_string().into()))
+        .await?;
+
+    let mut options = ClientOptions::default();
+    let mut last_media = LastMedia::default();
+    // Requests run concurrently; dropping the set on disconnect aborts
+    // whatever is still running. Responses are collected from the set and
+    // written here, so their frames never interleave. Prefetches yield
+    // `None`.
+    let mut tasks: JoinSet<Option<Response>> = JoinSet::new();
+    let mut requests = RunningRequests::default();
+    let (backlog, mut last_subtitle_id) = backlog_json(&state, None, BACKLOG_LIMIT).await;
+    ws_tx
+        .send(Message::Text(backlog.to_string().into()))
+        .await?;
+
+    let playback = playback_json(&*state.playback.read().await);
+    ws_tx
+        .send(Message::Text(playback.to_string().into()))
+        .await?;
+
     loop {
         tokio::select! {
-            Ok(sub) = subtitle_rx.recv() => {
-                let msg = serde_json::json!({
-                    "type": "subtitle",
-                    "id": sub.id,
-                    "subtitle": sub.text,
-                    "sub_start": sub.sub_start,
-                    "sub_end": sub.sub_end,
-                });
+            event = event_rx.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("[client:{}] Lagged, skipped {} events; resyncing", id, skipped);
+                        let lagged = serde_json::json!({ "type": "lagged", "skipped": skipped });
+                        ws_tx.send(Message::Text(lagged.to_string().into())).await?;
+
+                        let (backlog, last_id) =
+                            backlog_json(&state, last_subtitle_id, usize::MAX).await;
+                        ws_tx.send(Message::Text(backlog.to_string().into())).await?;
+                        last_subtitle_id = last_id.or(last_subtitle_id);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                };
+
+                let msg = match event {
+                    ServerEvent::Subtitle(sub) => {
+                        // Already delivered by a resync.
+                        if last_subtitle_id.is_some_and(|last| sub.id <= last) {
+                            continue;
+                        }
+                        last_subtitle_id = Some(sub.id);
+                        if options.prefetch {
+                            let prefetch = prefetch_media(state.clone(), sub.clone(), last_media.clone());
+                            tasks.spawn(async move {
+                                prefetch.await;
+                                None
+                            });
+                        }
+                        let mut msg = subtitle_json(&sub);
+                        msg["type"] = "subtitle".into();
+                        msg
+                    }
+                    ServerEvent::MpvStatus(status) => serde_json::json!({
+                        "type": "mpv_status",
+                        "status": status.as_str(),
+                    }),
+                    ServerEvent::PlaybackState(playback) => playback_json(&playback),
+                    ServerEvent::TrackLoaded { media_path, count } => serde_json::json!({
+                        "type": "subtitle_track_loaded",
+                        "media_path": media_path,
+                        "count": count,
+                    }),
+                };
                 ws_tx.send(Message::Text(msg.to_string().into())).await?;
             }
 
             Some(msg) = ws_rx.next() => {
                 let msg = msg?;
                 if let Message::Text(text) = msg {
-                    if let Some(response) = handle_request(&text, id, &state).await {
-                        ws_tx.send(Message::Text(response.into())).await?;
+                    let (req_id, request) = parse_request(&text);
+                    let result = match request {
+                        Ok(ProtocolRequest::Options { binary_media, prefetch }) => {
+                            if let Some(binary_media) = binary_media {
+                                options.binary_media = binary_media;
+                            }
+                            if let Some(prefetch) = prefetch {
+                                options.prefetch = prefetch;
+                            }
+                            Ok(serde_json::json!({
+                                "type": "options",
+                                "binary_media": options.binary_media,
+                                "prefetch": options.prefetch,
+                            })
+                            .into())
+                        }
+                        Ok(ProtocolRequest::Cancel) => match requests.cancel(req_id.as_ref(), id) {
+                            Ok(()) => continue,
+                            Err(e) => Err(e),
+                        },
+                        Ok(request) => match requests.check_new(req_id.as_ref()) {
+                            Ok(()) => {
+                                last_media.remember(&request);
+                                let key = req_id.clone();
+                                let state = state.clone();
+                                let handle = tasks.spawn(async move {
+                                    let result = process_request(request, id, &state).await;
+                                    Some(into_response(req_id, result, id))
+                                });
+                                requests.start(key.as_ref(), handle);
+                                continue;
+                            }
+                            Err(e) => Err(e),
+                        },
+                        Err(e) => Err(e),
+                    };
+                    let response = into_response(req_id, result, id);
+                    for msg in response.into_messages(&options) {
+                        ws_tx.send(msg).await?;
                     }
                 } else if msg.is_close() {
                     return Ok(());
                 }
             }
 
+            Some(joined) = tasks.join_next_with_id(), if !tasks.is_empty() => {
+                let response = match joined {
+                    Ok((task_id, response)) => {
+                        requests.finish(task_id);
+                        response
+                    }
+                    Err(e) => {
+                        let req_id = requests.finish(e.id());
+                        let error = if e.is_cancelled() {
+                            RequestError::new("cancelled", "Request was cancelled")
+                        } else {
+                            RequestError::new("internal", e.to_string())
+                        };
+                        Some(into_response(req_id, Err(error), id))
+                    }
+                };
+                for msg in response.into_iter().flat_map(|r| r.into_messages(&options)) {
+                    ws_tx.send(msg).await?;
+                }
+            }
+
             else => return Ok(()),
         }
     }
 }
 
+/// First message on every connection: what this server and its ffmpeg
+/// support.
+async fn hello_json() -> serde_json::Value {
+    serde_json::json!({
+        "type": "hello",
+        "version": env!("CARGO_PKG_VERSION"),
+        "protocol_version": PROTOCOL_VERSION,
+        "requests": REQUEST_TYPES,
+        "ffmpeg": crate::media::capabilities().await,
+    })
+}
+
+/// Captured subtitles of the current file with an id above `since_id`, oldest
+/// first, limited to the newest `limit`. Also returns the id of the last subtitle included.
+async fn backlog_json(
+    state: &SharedState,
+    since_id: Option<u64>,
+    limit: usize,
+) -> (serde_json::Value, Option<u64>) {
+    let media_path = state.media_path.read().await.clone();
+
+    let store = state.subtitles.read().await;
+    let subs: Vec<_> = media_path
+        .as_deref()
+        .into_iter()
+        .flat_map(|path| store.file(path))
+        .filter(|s| s.id > since_id.unwrap_or(0))
+        .collect();
+    let skip = subs.len().saturating_sub(limit);
+    let subs = &subs[skip..];
+    let last_id = subs.last().map(|s| s.id);
+    let subtitles: Vec<_> = subs.iter().map(|s| subtitle_json(s)).collect();
+    drop(store);
+
+    let msg = serde_json::json!({
+        "type": "backlog",
+        "media_path": media_path,
+        "since_id": since_id,
+        "subtitles": subtitles,
+    });
+    (msg, last_id)
+}
+
+fn playback_json(playback: &PlaybackState) -> serde_json::Value {
+    let mut msg = serde_json::to_value(playback).unwrap_or_default();
+    msg["type"] = "playback_state".into();
+    msg
+}
+
+/// `sub_start`/`sub_end` are in subtitle time; `media_start`/`media_end` are
+/// on the same timeline as `playback_state.time_pos`.
+fn subtitle_json(sub: &Subtitle) -> serde_json::Value {
+    let media_timing = sub.media_timing();
+    serde_json::json!({
+        "id": sub.id,
+        "subtitle": sub.text,
+        "subtitle_html": sub.html,
+        "sub_start": sub.sub_start,
+        "sub_end": sub.sub_end,
+        "media_start": media_timing.map(|(start, _)| start),
+        "media_end": media_timing.map(|(_, end)| end),
+        "translation": sub.translation,
+    })
+}
+
+/// `request` values accepted by `ProtocolRequest`, in declaration order,
+/// advertised in `hello`.
+const REQUEST_TYPES: [&str; 20] = [
+    "thumbnail",
+    "audio",
+    "audio_range",
+    "subtitle_track",
+    "history",
+    "subtitles_in_range",
+    "history_sessions",
+    "history_lines",
+    "history_audio",
+    "history_thumbnail",
+    "search",
+    "options",
+    "seek_to_subtitle",
+    "replay",
+    "pause",
+    "resume",
+    "seek",
+    "study_mode",
+    "export",
+    "cancel",
+];
+
 #[derive(Deserialize)]
 #[serde(tag = "request", rename_all = "snake_case")]
 enum ProtocolRequest {
@@ -355,12 +793,621 @@ enum ProtocolRequest {
         offset_end: Option<f64>,
         audio_config: Option<crate::media::AudioConfig>,
     },
+    SubtitleTrack,
+    History {
+        since_id: Option<u64>,
+        limit: Option<usize>,
+    },
+    /// `start` and `end` are media time, like `playback_state.time_pos`.
+    SubtitlesInRange {
+        start: f64,
+        end: f64,
+        media_path: Option<String>,
+    },
+    HistorySessions {
+        before_id: Option<i64>,
+        limit: Option<usize>,
+    },
+    HistoryLines {
+        session_id: i64,
+        after_id: Option<i64>,
+        limit: Option<usize>,
+    },
+    HistoryAudio {
+        history_id: i64,
+        offset_start: Option<f64>,
+        offset_end: Option<f64>,
+        audio_config: Option<crate::media::AudioConfig>,
+    },
+    HistoryThumbnail {
+        history_id: i64,
+        image_config: Option<crate::media::ImageConfig>,
+    },
+    Search {
+        query: String,
+        #[serde(default)]
+        mode: SearchMode,
+        media_path: Option<String>,
+        limit: Option<usize>,
+    },
+    Options {
+        binary_media: Option<bool>,
+        prefetch: Option<bool>,
+    },
+    SeekToSubtitle {
+        id: u64,
+    },
+    Replay {
+        id: u64,
+    },
+    Pause,
+    Resume,
+    Seek {
+        time: f64,
+    },
+    /// Fields left out keep their current value.
+    StudyMode {
+        enabled: Option<bool>,
+        min_length: Option<usize>,
+        unknown_words: Option<Vec<String>>,
+    },
+    Export {
+        #[serde(default)]
+        format: ExportFormat,
+        media_path: Option<String>,
+        start_id: Option<u64>,
+        end_id: Option<u64>,
+    },
+    /// Aborts the running request with the same `req_id`.
+    Cancel,
+}
+
+/// Default page size of `history_sessions` and `history_lines`.
+const HISTORY_PAGE_LIMIT: usize = 100;
+
+/// Default number of results per source of a `search` request.
+const SEARCH_LIMIT: usize = 50;
+
+/// Failure reported to the client as an `error` message.
+struct RequestError {
+    code: &'static str,
+    message: String,
+}
+
+impl RequestError {
+    fn new(code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn unknown_subtitle(id: u64) -> Self {
+        Self::new("unknown_subtitle", format!("No subtitle with id {}", id))
+    }
+
+    fn history_disabled() -> Self {
+        Self::new(
+            "history_disabled",
+            "Server was started without --history-db",
+        )
+    }
+}
+
+impl From<MpvError> for RequestError {
+    fn from(e: MpvError) -> Self {
+        Self::new("mpv_error", e.to_string())
+    }
+}
+
+impl From<FfmpegError> for RequestError {
+    fn from(e: FfmpegError) -> Self {
+        Self::new("ffmpeg_failed", e.to_string())
+    }
+}
+
+/// Per-connection settings chosen by the client with an `options` request.
+#[derive(Default)]
+struct ClientOptions {
+    /// Send media as a JSON header followed by a binary frame instead of
+    /// base64 inside the JSON.
+    binary_media: bool,
+    /// Generate media for each new line in the background, with the configs
+    /// of the client's last media requests.
+    prefetch: bool,
+}
+
+/// Configs of the client's last `thumbnail` and `audio` requests. `None`
+/// until the client asked for that kind of media.
+#[derive(Clone, Default)]
+struct LastMedia {
+    image_config: Option<ImageConfig>,
+    audio: Option<(Option<f64>, Option<f64>, AudioConfig)>,
+}
+
+impl LastMedia {
+    fn remember(&mut self, request: &ProtocolRequest) {
+        match request {
+            ProtocolRequest::Thumbnail { image_config, .. } => {
+                self.image_config = Some(image_config.clone().unwrap_or_default());
+            }
+            ProtocolRequest::Audio {
+                offset_start,
+                offset_end,
+                audio_config,
+                ..
+            } => {
+                self.audio = Some((
+                    *offset_start,
+                    *offset_end,
+                    audio_config.clone().unwrap_or_default(),
+                ));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reply to a request. Media bytes are kept out of the JSON body until the
+/// client's options say how to send them.
+struct Response {
+    body: serde_json::Value,
+    media: Option<Vec<u8>>,
+}
+
+impl Response {
+    fn media(body: serde_json::Value, data: Vec<u8>) -> Self {
+        Self {
+            body,
+            media: Some(data),
+        }
+    }
+
+    /// In binary mode the header carries `"binary": true` and the payload
+    /// size, and the payload follows as the very next frame.
+    fn into_messages(self, options: &ClientOptions) -> Vec<Message> {
+        let mut body = self.body;
+        let Some(data) = self.media else {
+            return vec![Message::Text(body.to_string().into())];
+        };
+        if options.binary_media {
+            body["binary"] = true.into();
+            body["size"] = data.len().into();
+            vec![
+                Message::Text(body.to_string().into()),
+                Message::Binary(data.into()),
+            ]
+        } else {
+            body["data"] = base64::engine::general_purpose::STANDARD
+                .encode(&data)
+                .into();
+            vec![Message::Text(body.to_string().into())]
+        }
+    }
+}
+
+impl From<serde_json::Value> for Response {
+    fn from(body: serde_json::Value) -> Self {
+        Self { body, media: None }
+    }
+}
+
+/// Parses one client message, along with its `req_id` if it had one.
+fn parse_request(
+    text: &str,
+) -> (
+    Option<serde_json::Value>,
+    Result<ProtocolRequest, RequestError>,
+) {
+    match serde_json::from_str::<serde_json::Value>(text) {
+        Ok(value) => {
+            let req_id = value.get("req_id").cloned();
+            let request = serde_json::from_value::<ProtocolRequest>(value)
+                .map_err(|e| RequestError::new("invalid_request", e.to_string()));
+            (req_id, request)
+        }
+        Err(e) => (None, Err(RequestError::new("parse_error", e.to_string()))),
+    }
+}
+
+/// Aborts the running request `req_id` and returns its task id. The request
+/// is still answered exactly once: with a `cancelled` error, or with its
+/// real response if it finished before the abort took effect.
+/// A connection's requests that have a `req_id`, which `cancel` can abort.
+#[derive(Default)]
+struct RunningRequests {
+    /// Keyed by the `req_id` as JSON text, so `1` and `"1"` differ.
+    running: HashMap<String, AbortHandle>,
+    /// `req_id` of each request a `cancel` aborted, answered once it stops.
+    cancelled: HashMap<tokio::task::Id, serde_json::Value>,
+}
+
+impl RunningRequests {
+    /// Refuses a `req_id` that a still running request already uses.
+    fn check_new(&self, req_id: Option<&serde_json::Value>) -> Result<(), RequestError> {
+        if req_id.is_some_and(|r| self.running.contains_key(&r.to_string())) {
+            return Err(RequestError::new(
+                "duplicate_req_id",
+                "A request with this req_id is still running",
+            ));
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, req_id: Option<&serde_json::Value>, handle: AbortHandle) {
+        if let Some(req_id) = req_id {
+            self.running.insert(req_id.to_string(), handle);
+        }
+    }
+
+    fn cancel(
+        &mut self,
+        req_id: Option<&serde_json::Value>,
+        client_id: u64,
+    ) -> Result<(), RequestError> {
+        let Some(req_id) = req_id else {
+            return Err(RequestError::new(
+                "invalid_request",
+                "cancel needs the req_id of the request to cancel",
+            ));
+        };
+        let Some(handle) = self.running.get(&req_id.to_string()) else {
+            return Err(RequestError::new(
+                "not_running",
+                format!("No running request with req_id {}", req_id),
+            ));
+        };
+        handle.abort();
+        info!("[client:{}] Cancelled request {}", client_id, req_id);
+        self.cancelled.insert(handle.id(), req_id.clone());
+        Ok(())
+    }
+
+    /// Forgets a task that stopped. Returns its `req_id` if `cancel` aborted
+    /// it.
+    fn finish(&mut self, task_id: tokio::task::Id) -> Option<serde_json::Value> {
+        self.running.retain(|_, handle| handle.id() != task_id);
+        self.cancelled.remove(&task_id)
+    }
 }
 
-async fn handle_request(text: &str, client_id: u64, state: &Arc<SharedState>) -> Option<String> {
-    let request: ProtocolRequest = serde_json::from_str(text).ok()?;
+/// Turns a request's outcome into the message sent back. The response echoes
+/// the request's `req_id`, if it had one, so clients can match it up.
+fn into_response(
+    req_id: Option<serde_json::Value>,
+    result: Result<Response, RequestError>,
+    client_id: u64,
+) -> Response {
+    let mut response = result.unwrap_or_else(|e| {
+        warn!(
+            "[client:{}] Request failed ({}): {}",
+            client_id, e.code, e.message
+        );
+        serde_json::json!({
+            "type": "error",
+            "req_id": null,
+            "code": e.code,
+            "message": e.message,
+        })
+        .into()
+    });
+    if let Some(req_id) = req_id {
+        response.body["req_id"] = req_id;
+    }
+    response
+}
 
+async fn process_request(
+    request: ProtocolRequest,
+    client_id: u64,
+    state: &Arc<SharedState>,
+) -> Result<Response, RequestError> {
     match request {
+        ProtocolRequest::History { since_id, limit } => {
+            let (backlog, _) = backlog_json(state, since_id, limit.unwrap_or(BACKLOG_LIMIT)).await;
+            Ok(backlog.into())
+        }
+        ProtocolRequest::SubtitlesInRange {
+            start,
+            end,
+            media_path,
+        } => {
+            let media_path = match media_path {
+                Some(path) => Some(path),
+                None => state.media_path.read().await.clone(),
+            };
+            let store = state.subtitles.read().await;
+            let track = state.track.read().await;
+            let mut subs: Vec<_> = media_path
+                .as_deref()
+                .map(|path| {
+                    let mut subs = store.range(path, start, end);
+                    subs.extend(track.range(path, start, end));
+                    subs
+                })
+                .unwrap_or_default();
+            let media_start = |s: &Subtitle| s.media_timing().map_or(0.0, |(start, _)| start);
+            subs.sort_by(|a, b| media_start(a).total_cmp(&media_start(b)));
+            let subtitles: Vec<_> = subs.into_iter().map(subtitle_json).collect();
+            drop(track);
+            drop(store);
+
+            Ok(serde_json::json!({
+                "type": "subtitles_in_range",
+                "media_path": media_path,
+                "start": start,
+                "end": end,
+                "subtitles": subtitles,
+            })
+            .into())
+        }
+        ProtocolRequest::SubtitleTrack => {
+            let track = state.track.read().await;
+            let media_path = track.media_path.clone();
+            let subtitles: Vec<_> = track.cues.values().map(subtitle_json).collect();
+            drop(track);
+
+            info!(
+                "[client:{}] Requesting subtitle_track ({} cues)",
+                client_id,
+                subtitles.len()
+            );
+
+            Ok(serde_json::json!({
+                "type": "subtitle_track",
+                "media_path": media_path,
+                "subtitles": subtitles,
+            })
+            .into())
+        }
+        ProtocolRequest::HistorySessions { before_id, limit } => {
+            let history = state
+                .history
+                .clone()
+                .ok_or_else(RequestError::history_disabled)?;
+            let limit = limit.unwrap_or(HISTORY_PAGE_LIMIT);
+            let sessions = run_history(move || history.sessions(before_id, limit)).await?;
+            Ok(serde_json::json!({
+                "type": "history_sessions",
+                "before_id": before_id,
+                "sessions": sessions,
+            })
+            .into())
+        }
+        ProtocolRequest::HistoryLines {
+            session_id,
+            after_id,
+            limit,
+        } => {
+            let history = state
+                .history
+                .clone()
+                .ok_or_else(RequestError::history_disabled)?;
+            let limit = limit.unwrap_or(HISTORY_PAGE_LIMIT);
+            let lines = run_history(move || history.lines(session_id, after_id, limit)).await?;
+            Ok(serde_json::json!({
+                "type": "history_lines",
+                "session_id": session_id,
+                "after_id": after_id,
+                "lines": lines,
+            })
+            .into())
+        }
+        ProtocolRequest::Search {
+            query,
+            mode,
+            media_path,
+            limit,
+        } => {
+            let search = SearchQuery::new(&query, mode);
+            let limit = limit.unwrap_or(SEARCH_LIMIT);
+
+            let store = state.subtitles.read().await;
+            let results: Vec<_> = if search.is_empty() {
+                Vec::new()
+            } else {
+                store
+                    .search(&search, media_path.as_deref(), limit)
+                    .into_iter()
+                    .map(|sub| {
+                        let mut result = subtitle_json(sub);
+                        result["media_path"] = sub.media_path.clone().into();
+                        result["media_title"] = sub.media_title.clone().into();
+                        result
+                    })
+                    .collect()
+            };
+            drop(store);
+
+            // History ids work with `history_audio` / `history_thumbnail`.
+            let history = match state.history.clone() {
+                Some(history) if !search.is_empty() => {
+                    let path = media_path.clone();
+                    Some(
+                        run_history(move || history.search(&search, path.as_deref(), limit))
+                            .await?,
+                    )
+                }
+                _ => None,
+            };
+
+            info!(
+                "[client:{}] Searching for '{}' ({} results)",
+                client_id,
+                query,
+                results.len()
+            );
+
+            Ok(serde_json::json!({
+                "type": "search",
+                "query": query,
+                "mode": mode.as_str(),
+                "media_path": media_path,
+                "results": results,
+                "history": history,
+            })
+            .into())
+        }
+        ProtocolRequest::Export {
+            format,
+            media_path,
+            start_id,
+            end_id,
+        } => {
+            let store = state.subtitles.read().await;
+            let files = match &media_path {
+                Some(path) => vec![path.as_str()],
+                None => store.files(),
+            };
+            let subs: Vec<_> = files
+                .into_iter()
+                .flat_map(|path| store.file(path))
+                .filter(|s| start_id.is_none_or(|id| s.id >= id))
+                .filter(|s| end_id.is_none_or(|id| s.id <= id))
+                .collect();
+            let count = subs.len();
+            let data = export::export(&subs, format);
+            drop(store);
+
+            info!(
+                "[client:{}] Exporting {} subtitles as {}",
+                client_id,
+                count,
+                format.as_str()
+            );
+
+            Ok(serde_json::json!({
+                "type": "export",
+                "format": format.as_str(),
+                "media_path": media_path,
+                "count": count,
+                "data": data,
+            })
+            .into())
+        }
+        ProtocolRequest::SeekToSubtitle { id } | ProtocolRequest::Replay { id } => {
+            let replay = matches!(request, ProtocolRequest::Replay { .. });
+            let (start, end) = playable_timing(state, id).await?;
+            let generation = state.playback_generation.fetch_add(1, Ordering::Relaxed) + 1;
+
+            seek(&state.mpv, start).await?;
+            if replay {
+                state.mpv.set_property("pause", false).await?;
+                tokio::spawn(pause_at(state.clone(), generation, start, end));
+            }
+
+            let command = if replay { "replay" } else { "seek_to_subtitle" };
+            info!(
+                "[client:{}] {} subtitle {} at {:.3}",
+                client_id, command, id, start
+            );
+            Ok(serde_json::json!({
+                "type": "ack",
+                "request": command,
+                "id": id,
+                "sub_start": start,
+                "sub_end": end,
+            })
+            .into())
+        }
+        ProtocolRequest::Pause | ProtocolRequest::Resume => {
+            let pause = matches!(request, ProtocolRequest::Pause);
+            state.playback_generation.fetch_add(1, Ordering::Relaxed);
+            state.mpv.set_property("pause", pause).await?;
+
+            let command = if pause { "pause" } else { "resume" };
+            debug!("[client:{}] {}", client_id, command);
+            Ok(serde_json::json!({ "type": "ack", "request": command }).into())
+        }
+        ProtocolRequest::Seek { time } => {
+            state.playback_generation.fetch_add(1, Ordering::Relaxed);
+            seek(&state.mpv, time).await?;
+
+            debug!("[client:{}] seek to {:.3}", client_id, time);
+            Ok(serde_json::json!({ "type": "ack", "request": "seek", "time": time }).into())
+        }
+        ProtocolRequest::StudyMode {
+            enabled,
+            min_length,
+            unknown_words,
+        } => {
+            state.study.send_modify(|settings| {
+                if let Some(enabled) = enabled {
+                    settings.enabled = enabled;
+                }
+                if let Some(min_length) = min_length {
+                    settings.min_length = (min_length > 0).then_some(min_length);
+                }
+                if let Some(words) = unknown_words {
+                    settings.unknown_words = words;
+                }
+            });
+            let settings = state.study.borrow().clone();
+
+            info!(
+                "[client:{}] Study mode {} (min_length: {:?}, {} unknown words)",
+                client_id,
+                if settings.enabled { "on" } else { "off" },
+                settings.min_length,
+                settings.unknown_words.len()
+            );
+            let mut msg = serde_json::to_value(settings).unwrap_or_default();
+            msg["type"] = "study_mode".into();
+            Ok(msg.into())
+        }
+        ProtocolRequest::HistoryAudio { history_id, .. }
+        | ProtocolRequest::HistoryThumbnail { history_id, .. } => {
+            let history = state
+                .history
+                .clone()
+                .ok_or_else(RequestError::history_disabled)?;
+            let line = run_history(move || history.line(history_id))
+                .await?
+                .ok_or_else(|| {
+                    RequestError::new(
+                        "unknown_history_entry",
+                        format!("No history entry with id {}", history_id),
+                    )
+                })?;
+            let sub = line.to_subtitle();
+            let (media_type, ffmpeg_req) = match request {
+                ProtocolRequest::HistoryAudio {
+                    offset_start,
+                    offset_end,
+                    audio_config,
+                    ..
+                } => (
+                    "history_audio",
+                    FfmpegRequest::audio(&sub, offset_start, offset_end, audio_config),
+                ),
+                ProtocolRequest::HistoryThumbnail { image_config, .. } => (
+                    "history_thumbnail",
+                    FfmpegRequest::thumbnail(&sub, image_config),
+                ),
+                _ => unreachable!(),
+            };
+
+            info!(
+                "[client:{}] Requesting {} for history entry {}",
+                client_id, media_type, history_id
+            );
+
+            if !media_available(&line.media_path) {
+                return Err(RequestError::new(
+                    "media_missing",
+                    format!("Media file no longer exists: {}", line.media_path),
+                ));
+            }
+            let data = execute_ffmpeg(state, ffmpeg_req).await?;
+
+            Ok(Response::media(
+                serde_json::json!({
+                    "type": media_type,
+                    "history_id": history_id,
+                }),
+                data,
+            ))
+        }
         ProtocolRequest::AudioRange {
             start_id,
             end_id,
@@ -368,50 +1415,53 @@ async fn handle_request(text: &str, client_id: u64, state: &Arc<SharedState>) ->
             offset_end,
             audio_config,
         } => {
-            let store = state.subtitles.read().await;
-            let start = store.get(&start_id)?;
-            let end = store.get(&end_id)?;
-            let ffmpeg_req = FfmpegRequest::audio_range(
-                start.sub_start,
-                end.sub_end,
-                &start.media_path,
-                start.aid,
-                offset_start,
-                offset_end,
-                audio_config,
-            );
-            drop(store);
+            let start = state.subtitle(start_id).await?;
+            let end = state.subtitle(end_id).await?;
+            let ffmpeg_req = match (
+                start.audio_timing(),
+                end.audio_timing(),
+                start.audio_input(),
+            ) {
+                (Some((audio_start, _)), Some((_, audio_end)), Some(source)) => {
+                    Some(FfmpegRequest::audio_range(
+                        audio_start,
+                        audio_end,
+                        &source,
+                        offset_start,
+                        offset_end,
+                        audio_config,
+                    ))
+                }
+                _ => None,
+            };
 
             info!(
                 "[client:{}] Requesting audio_range from subtitle {} to {}",
                 client_id, start_id, end_id
             );
 
-            let data = tokio::task::spawn_blocking(move || ffmpeg_req.execute())
-                .await
-                .ok()?;
+            let data = execute_ffmpeg(state, ffmpeg_req).await?;
 
-            Some(
+            Ok(Response::media(
                 serde_json::json!({
                     "type": "audio_range",
                     "start_id": start_id,
                     "end_id": end_id,
-                    "data": data,
-                })
-                .to_string(),
-            )
+                }),
+                data,
+            ))
         }
         _ => {
             let (subtitle_id, media_type, ffmpeg_req) = match request {
-                ProtocolRequest::Thumbnail { id, end_id, image_config } => {
-                    let store = state.subtitles.read().await;
-                    let mut sub = store.get(&id)?.clone();
+                ProtocolRequest::Thumbnail {
+                    id,
+                    end_id,
+                    image_config,
+                } => {
+                    let mut sub = state.subtitle(id).await?;
                     if let Some(eid) = end_id {
-                        if let Some(end_sub) = store.get(&eid) {
-                            sub.sub_end = end_sub.sub_end;
-                        }
+                        sub.sub_end = state.subtitle(eid).await?.sub_end;
                     }
-                    drop(store);
                     (
                         id,
                         "thumbnail",
@@ -424,9 +1474,7 @@ async fn handle_request(text: &str, client_id: u64, state: &Arc<SharedState>) ->
                     offset_end,
                     audio_config,
                 } => {
-                    let store = state.subtitles.read().await;
-                    let sub = store.get(&id)?.clone();
-                    drop(store);
+                    let sub = state.subtitle(id).await?;
                     (
                         id,
                         "audio",
@@ -442,27 +1490,360 @@ async fn handle_request(text: &str, client_id: u64, state: &Arc<SharedState>) ->
             );
 
             let req_type = media_type.to_string();
-            let data = tokio::task::spawn_blocking(move || ffmpeg_req.execute())
-                .await
-                .ok()?;
-
-            if data.is_some() {
-                debug!("[media] {} ready for subtitle {}", req_type, subtitle_id);
-            } else {
+            let data = execute_ffmpeg(state, ffmpeg_req).await.inspect_err(|_| {
                 warn!(
                     "[media] Failed to generate {} for subtitle {}",
                     req_type, subtitle_id
                 );
-            }
+            })?;
+            debug!("[media] {} ready for subtitle {}", req_type, subtitle_id);
 
-            Some(
+            Ok(Response::media(
                 serde_json::json!({
                     "type": req_type,
                     "id": subtitle_id,
-                    "data": data,
-                })
-                .to_string(),
-            )
+                }),
+                data,
+            ))
+        }
+    }
+}
+
+/// Timing of subtitle `id`, which must belong to the file mpv is playing.
+async fn playable_timing(state: &SharedState, id: u64) -> Result<(f64, f64), RequestError> {
+    let sub = state.subtitle(id).await?;
+    let timing = sub.media_timing().ok_or_else(|| {
+        RequestError::new("missing_metadata", format!("Subtitle {} has no timing", id))
+    })?;
+    let media_path = sub.media_path;
+
+    if media_path != *state.media_path.read().await {
+        return Err(RequestError::new(
+            "not_playing",
+            format!("Subtitle {} is from a file mpv isn't playing", id),
+        ));
+    }
+    Ok(timing)
+}
+
+async fn seek(mpv: &MpvClient, time: f64) -> Result<(), MpvError> {
+    mpv.command(&["seek".into(), time.into(), "absolute+exact".into()])
+        .await
+        .map(|_| ())
+}
+
+/// Shortest time `pause_at` waits, also used when the line's span isn't a
+/// usable duration.
+const REPLAY_MIN_WAIT: Duration = Duration::from_secs(5);
+
+/// Pauses mpv once playback reaches `end`, following the observed
+/// `time-pos`. Gives up if another playback command was issued, the user
+/// paused or seeked away, or playback takes far longer than the line itself.
+async fn pause_at(state: Arc<SharedState>, generation: u64, start: f64, end: f64) {
+    let limit = Duration::try_from_secs_f64((end - start) * 4.0)
+        .map_or(REPLAY_MIN_WAIT, |span| span + REPLAY_MIN_WAIT);
+    let mut position = state.position.subscribe();
+    let current = || state.playback_generation.load(Ordering::Relaxed) == generation;
+    let reached_end = tokio::time::timeout(limit, wait_for_end(&mut position, start, end, current));
+    if reached_end.await == Ok(true)
+        && let Err(e) = state.mpv.set_property("pause", true).await
+    {
+        warn!("[mpv] Failed to pause after replay: {}", e);
+    }
+}
+
+/// Follows `position` through a replay of `start..end`. True once playback
+/// gets from near `start` to `end`; false if `current` turns false or the
+/// user pauses or seeks away first.
+async fn wait_for_end(
+    position: &mut watch::Receiver<Position>,
+    start: f64,
+    end: f64,
+    current: impl Fn() -> bool,
+) -> bool {
+    // time-pos and pause may still report the state from before the seek
+    // and unpause, which is often just past `end` when the line that just
+    // ended is replayed. Nothing counts until the seek has landed.
+    let mut reached_start = false;
+    let mut playing = false;
+
+    while position.changed().await.is_ok() {
+        if !current() {
+            return false;
+        }
+        let Position { time_pos, paused } = *position.borrow_and_update();
+        let Some(pos) = time_pos else {
+            continue;
+        };
+        if !reached_start {
+            reached_start = pos >= start - 1.0 && pos < end.min(start + 1.0);
+            if !reached_start {
+                continue;
+            }
+        }
+        if pos < start - 1.0 || pos > end + 1.0 {
+            debug!("[mpv] Replay interrupted by a seek");
+            return false;
+        }
+        if pos >= end {
+            return true;
+        }
+        if paused && playing {
+            return false;
+        }
+        playing |= !paused;
+    }
+    false
+}
+
+/// Runs a history query off the async runtime.
+async fn run_history<T, F>(query: F) -> Result<T, RequestError>
+where
+    F: FnOnce() -> rusqlite::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(query).await {
+        Ok(result) => result.map_err(|e| RequestError::new("history_failed", e.to_string())),
+        Err(e) => Err(RequestError::new("internal", e.to_string())),
+    }
+}
+
+/// Whether a recorded media path can still be opened. URLs are assumed to
+/// be reachable.
+fn media_available(media_path: &str) -> bool {
+    media_path.contains("://") || std::path::Path::new(media_path).exists()
+}
+
+/// Runs `req` for a client. `None` means the subtitle lacked the timing,
+/// path or audio track needed to build the request.
+async fn execute_ffmpeg(
+    state: &SharedState,
+    req: Option<FfmpegRequest>,
+) -> Result<Vec<u8>, RequestError> {
+    let Some(req) = req else {
+        return Err(RequestError::new(
+            "missing_metadata",
+            "Subtitle is missing the timing, media path or audio track required for ffmpeg",
+        ));
+    };
+    run_ffmpeg(state, req).await
+}
+
+/// Generates the media `last` says the client will likely ask for about
+/// `sub` into the media cache.
+async fn prefetch_media(state: Arc<SharedState>, sub: Subtitle, last: LastMedia) {
+    if let Some(image_config) = last.image_config {
+        prefetch(&state, FfmpegRequest::thumbnail(&sub, Some(image_config))).await;
+    }
+    if let Some((offset_start, offset_end, audio_config)) = last.audio {
+        let req = FfmpegRequest::audio(&sub, offset_start, offset_end, Some(audio_config));
+        prefetch(&state, req).await;
+    }
+}
+
+/// Runs `req` into the media cache, but only on an idle ffmpeg slot, so
+/// prefetching never holds up requests clients are waiting for.
+async fn prefetch(state: &SharedState, req: Option<FfmpegRequest>) {
+    let Some((req, key)) = req.and_then(|req| {
+        let key = req.cache_key()?.clone();
+        Some((req, key))
+    }) else {
+        return;
+    };
+    let input_modified = key.input_modified().await;
+    if state
+        .media_cache
+        .lock()
+        .unwrap()
+        .contains(&key, input_modified)
+    {
+        return;
+    }
+    let Ok(_permit) = state.ffmpeg_jobs.try_acquire() else {
+        debug!("[cache] No idle ffmpeg slot, skipping prefetch");
+        return;
+    };
+    let (done, waiting) = watch::channel(());
+    match state.prefetching.lock().unwrap().entry(key.clone()) {
+        hash_map::Entry::Occupied(_) => return,
+        hash_map::Entry::Vacant(entry) => {
+            entry.insert(waiting);
+        }
+    }
+    let _in_flight = InFlight {
+        state,
+        key: key.clone(),
+        _done: done,
+    };
+    if let Ok(Ok(data)) = tokio::time::timeout(state.ffmpeg_timeout, req.run()).await {
+        state
+            .media_cache
+            .lock()
+            .unwrap()
+            .insert(key, data, input_modified);
+    }
+}
+
+/// Clears a prefetch from `SharedState::prefetching` however it ends,
+/// including when the client disconnects mid-run. Dropping `_done` wakes
+/// the requests waiting for it.
+struct InFlight<'a> {
+    state: &'a SharedState,
+    key: CacheKey,
+    _done: watch::Sender<()>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.state.prefetching.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Answers from the media cache if possible, waiting for a prefetch of the
+/// same result first. Otherwise waits for a free ffmpeg slot, then runs
+/// `req` with the configured timeout. ffmpeg is killed if it times out or
+/// the caller goes away.
+async fn run_ffmpeg(state: &SharedState, req: FfmpegRequest) -> Result<Vec<u8>, RequestError> {
+    let cache_key = match req.cache_key() {
+        Some(key) => Some((key.clone(), key.input_modified().await)),
+        None => None,
+    };
+    if let Some((key, input_modified)) = &cache_key {
+        let prefetch = state.prefetching.lock().unwrap().get(key).cloned();
+        if let Some(mut prefetch) = prefetch {
+            debug!("[cache] Waiting for the running prefetch");
+            let _ = prefetch.changed().await;
+        }
+        if let Some(data) = state.media_cache.lock().unwrap().get(key, *input_modified) {
+            return Ok(data);
+        }
+    }
+
+    let _permit = state
+        .ffmpeg_jobs
+        .acquire()
+        .await
+        .map_err(|e| RequestError::new("internal", e.to_string()))?;
+    match tokio::time::timeout(state.ffmpeg_timeout, req.run()).await {
+        Ok(result) => {
+            let data = result?;
+            if let Some((key, input_modified)) = cache_key {
+                state
+                    .media_cache
+                    .lock()
+                    .unwrap()
+                    .insert(key, data.clone(), input_modified);
+            }
+            Ok(data)
+        }
+        Err(_) => Err(RequestError::new(
+            "timeout",
+            format!(
+                "ffmpeg did not finish within {}s",
+                state.ffmpeg_timeout.as_secs()
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advertised_requests_are_the_protocol_requests() {
+        // serde lists every variant name, in declaration order, when it meets
+        // an unknown one.
+        let err = serde_json::from_value::<ProtocolRequest>(serde_json::json!({ "request": "?" }))
+            .err()
+            .unwrap()
+            .to_string();
+        let variants: Vec<_> = err.split('`').skip(3).step_by(2).collect();
+        assert_eq!(variants, REQUEST_TYPES);
+    }
+
+    fn code<T>(result: Result<T, RequestError>) -> &'static str {
+        result.err().map_or("ok", |e| e.code)
+    }
+
+    #[tokio::test]
+    async fn cancel_needs_a_running_req_id() {
+        let mut requests = RunningRequests::default();
+
+        assert_eq!(code(requests.cancel(None, 0)), "invalid_request");
+        assert_eq!(code(requests.cancel(Some(&"a".into()), 0)), "not_running");
+    }
+
+    #[tokio::test]
+    async fn cancel_after_completion_is_refused() {
+        let mut requests = RunningRequests::default();
+        let mut tasks = JoinSet::new();
+        let req_id = serde_json::Value::from("a");
+        requests.start(Some(&req_id), tasks.spawn(async {}));
+
+        let (task_id, ()) = tasks.join_next_with_id().await.unwrap().unwrap();
+        assert_eq!(requests.finish(task_id), None);
+        assert_eq!(code(requests.cancel(Some(&req_id), 0)), "not_running");
+        assert_eq!(code(requests.check_new(Some(&req_id))), "ok");
+    }
+
+    #[tokio::test]
+    async fn req_id_is_taken_until_the_request_stops() {
+        let mut requests = RunningRequests::default();
+        let mut tasks = JoinSet::new();
+        let req_id = serde_json::Value::from("a");
+        requests.start(Some(&req_id), tasks.spawn(std::future::pending::<()>()));
+
+        assert_eq!(code(requests.check_new(Some(&req_id))), "duplicate_req_id");
+        assert_eq!(code(requests.check_new(Some(&1.into()))), "ok");
+        assert_eq!(code(requests.check_new(None)), "ok");
+
+        assert_eq!(code(requests.cancel(Some(&req_id), 0)), "ok");
+        let e = tasks.join_next_with_id().await.unwrap().unwrap_err();
+        assert!(e.is_cancelled());
+        assert_eq!(requests.finish(e.id()), Some(req_id.clone()));
+        assert_eq!(code(requests.check_new(Some(&req_id))), "ok");
+    }
+
+    fn at(time_pos: f64) -> Position {
+        Position {
+            time_pos: Some(time_pos),
+            paused: false,
         }
     }
+
+    /// Whether `wait` is still waiting after everything sent so far was seen.
+    async fn waiting(wait: impl Future<Output = bool>) -> bool {
+        tokio::time::timeout(Duration::from_millis(20), wait)
+            .await
+            .is_err()
+    }
+
+    #[tokio::test]
+    async fn replay_ignores_the_position_from_before_the_seek() {
+        let (tx, mut rx) = watch::channel(Position::default());
+        let wait = wait_for_end(&mut rx, 8.0, 10.0, || true);
+        tokio::pin!(wait);
+
+        tx.send(at(10.3)).unwrap();
+        assert!(waiting(&mut wait).await);
+        tx.send(at(8.0)).unwrap();
+        assert!(waiting(&mut wait).await);
+        tx.send(at(9.5)).unwrap();
+        assert!(waiting(&mut wait).await);
+        tx.send(at(10.0)).unwrap();
+        assert!(wait.await);
+    }
+
+    #[tokio::test]
+    async fn replay_stops_when_seeked_away() {
+        let (tx, mut rx) = watch::channel(Position::default());
+        let wait = wait_for_end(&mut rx, 8.0, 10.0, || true);
+        tokio::pin!(wait);
+
+        tx.send(at(8.1)).unwrap();
+        assert!(waiting(&mut wait).await);
+        tx.send(at(30.0)).unwrap();
+        assert!(!wait.await);
+    }
 }
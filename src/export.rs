@@ -0,0 +1,201 @@
+//! Transcript export of captured subtitles, both as a protocol request and
+//! through the `export` subcommand, which asks a running server for it.
+
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::fmt::Write;
+use std::time::Duration;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use crate::event_loop::Subtitle;
+
+#[derive(Deserialize, clap::ValueEnum, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Srt,
+    Vtt,
+    Tsv,
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExportFormat::Srt => "srt",
+            ExportFormat::Vtt => "vtt",
+            ExportFormat::Tsv => "tsv",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+/// Serializes `subs` in order. SRT and WebVTT need timings, so lines without
+/// them are left out of those formats.
+pub fn export(subs: &[&Subtitle], format: ExportFormat) -> String {
+    let mut out = String::new();
+    match format {
+        ExportFormat::Srt => {
+            let timed = subs.iter().filter_map(|s| Some((s.timing()?, s)));
+            for (n, ((start, end), sub)) in timed.enumerate() {
+                let _ = write!(
+                    out,
+                    "{}\n{} --> {}\n{}\n\n",
+                    n + 1,
+                    timestamp(start, ','),
+                    timestamp(end, ','),
+                    cue_text(&sub.text)
+                );
+            }
+        }
+        ExportFormat::Vtt => {
+            out.push_str("WEBVTT\n\n");
+            for sub in subs {
+                let Some((start, end)) = sub.timing() else {
+                    continue;
+                };
+                let _ = write!(
+                    out,
+                    "{} --> {}\n{}\n\n",
+                    timestamp(start, '.'),
+                    timestamp(end, '.'),
+                    cue_text(&sub.text).replace("-->", "->")
+                );
+            }
+        }
+        ExportFormat::Tsv => {
+            out.push_str("id\tstart\tend\ttext\ttranslation\n");
+            for sub in subs {
+                let _ = writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}",
+                    sub.id,
+                    sub.sub_start
+                        .map(|t| format!("{:.3}", t))
+                        .unwrap_or_default(),
+                    sub.sub_end.map(|t| format!("{:.3}", t)).unwrap_or_default(),
+                    single_line(&sub.text),
+                    sub.translation
+                        .as_deref()
+                        .map(single_line)
+                        .unwrap_or_default()
+                );
+            }
+        }
+        ExportFormat::Jsonl => {
+            for sub in subs {
+                let line = serde_json::json!({
+                    "id": sub.id,
+                    "text": sub.text,
+                    "sub_start": sub.sub_start,
+                    "sub_end": sub.sub_end,
+                    "media_path": sub.media_path,
+                    "translation": sub.translation,
+                });
+                let _ = writeln!(out, "{}", line);
+            }
+        }
+    }
+    out
+}
+
+/// `HH:MM:SS,mmm` (SRT) or `HH:MM:SS.mmm` (WebVTT).
+fn timestamp(seconds: f64, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+/// Keeps line breaks but drops blank lines, which would end the cue early.
+fn cue_text(text: &str) -> String {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Merges all lines into one, for formats with one record per line.
+fn single_line(text: &str) -> String {
+    text.split(['\n', '\r', '\t'])
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Tags the export request so its reply, including an error, can be told
+/// apart from the broadcasts every client receives.
+const FETCH_REQ_ID: &str = "export";
+
+/// Longest to wait for the server's reply.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends `request` to the server on `port` and returns the exported text.
+pub async fn fetch(port: u16, mut request: serde_json::Value) -> std::io::Result<String> {
+    request["req_id"] = FETCH_REQ_ID.into();
+    tokio::time::timeout(FETCH_TIMEOUT, fetch_response(port, request))
+        .await
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("no reply from server within {}s", FETCH_TIMEOUT.as_secs()),
+            )
+        })?
+}
+
+async fn fetch_response(port: u16, request: serde_json::Value) -> std::io::Result<String> {
+    let (mut ws, _) = connect_async(format!("ws://127.0.0.1:{}", port))
+        .await
+        .map_err(std::io::Error::other)?;
+    ws.send(Message::Text(request.to_string().into()))
+        .await
+        .map_err(std::io::Error::other)?;
+
+    while let Some(msg) = ws.next().await {
+        let Message::Text(text) = msg.map_err(std::io::Error::other)? else {
+            continue;
+        };
+        let Ok(response) = serde_json::from_str::<serde_json::Value>(&text) else {
+            continue;
+        };
+        if response["req_id"] != FETCH_REQ_ID {
+            continue;
+        }
+        let _ = ws.close(None).await;
+        if response["type"] == "error" {
+            return Err(std::io::Error::other(format!(
+                "server refused export ({}): {}",
+                response["code"].as_str().unwrap_or_default(),
+                response["message"].as_str().unwrap_or_default()
+            )));
+        }
+        return Ok(response["data"].as_str().unwrap_or_default().to_string());
+    }
+    Err(std::io::Error::other("server closed the connection"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_use_the_format_separator() {
+        assert_eq!(timestamp(3723.045, ','), "01:02:03,045");
+        assert_eq!(timestamp(3723.045, '.'), "01:02:03.045");
+    }
+
+    #[test]
+    fn timestamps_round_to_milliseconds_and_clamp_at_zero() {
+        assert_eq!(timestamp(59.9996, ','), "00:01:00,000");
+        assert_eq!(timestamp(0.0004, ','), "00:00:00,000");
+        assert_eq!(timestamp(-1.5, ','), "00:00:00,000");
+        assert_eq!(timestamp(360_000.0, '.'), "100:00:00.000");
+    }
+}
@@ -0,0 +1,504 @@
+use log::{debug, info};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::event_loop::{Subtitle, TimingOffsets};
+use crate::search::SearchQuery;
+use crate::track_list::TrackSource;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id          INTEGER PRIMARY KEY,
+    media_path  TEXT NOT NULL,
+    media_title TEXT,
+    started_at  INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS lines (
+    id          INTEGER PRIMARY KEY,
+    session_id  INTEGER NOT NULL REFERENCES sessions(id),
+    text        TEXT NOT NULL,
+    sub_start   REAL,
+    sub_end     REAL,
+    aid         INTEGER,
+    sid         INTEGER,
+    translation TEXT,
+    captured_at INTEGER NOT NULL,
+    sub_delay   REAL NOT NULL DEFAULT 0,
+    sub_speed   REAL NOT NULL DEFAULT 1,
+    audio_delay REAL NOT NULL DEFAULT 0,
+    audio_input TEXT,
+    audio_stream TEXT
+);
+CREATE INDEX IF NOT EXISTS lines_by_session ON lines(session_id, id);
+";
+
+/// Trigram index over line text, so substring queries (including CJK text,
+/// which has no word boundaries) don't scan the whole table. Terms shorter
+/// than a trigram can't use it; see `History::search`.
+const FTS_SCHEMA: &str = "
+CREATE VIRTUAL TABLE lines_fts USING fts5(
+    text, content='lines', content_rowid='id', tokenize='trigram'
+);
+CREATE TRIGGER lines_fts_insert AFTER INSERT ON lines BEGIN
+    INSERT INTO lines_fts(rowid, text) VALUES (new.id, new.text);
+END;
+CREATE TRIGGER lines_fts_delete AFTER DELETE ON lines BEGIN
+    INSERT INTO lines_fts(lines_fts, rowid, text) VALUES ('delete', old.id, old.text);
+END;
+INSERT INTO lines_fts(lines_fts) VALUES ('rebuild');
+";
+
+/// One uninterrupted stretch of playback of a single file.
+#[derive(Serialize)]
+pub struct Session {
+    pub id: i64,
+    pub media_path: String,
+    pub media_title: Option<String>,
+    pub started_at: i64,
+    pub line_count: i64,
+}
+
+#[derive(Serialize)]
+pub struct HistoryLine {
+    pub id: i64,
+    pub session_id: i64,
+    #[serde(rename = "subtitle")]
+    pub text: String,
+    pub sub_start: Option<f64>,
+    pub sub_end: Option<f64>,
+    pub aid: Option<i64>,
+    pub sid: Option<i64>,
+    pub translation: Option<String>,
+    pub captured_at: i64,
+    #[serde(skip)]
+    pub offsets: TimingOffsets,
+    #[serde(skip)]
+    pub audio_source: Option<TrackSource>,
+    pub media_path: String,
+    pub media_title: Option<String>,
+}
+
+impl HistoryLine {
+    /// Rebuilds a `Subtitle` for media requests. The id is the history id,
+    /// which is unrelated to live subtitle ids.
+    pub fn to_subtitle(&self) -> Subtitle {
+        Subtitle {
+            id: self.id as u64,
+            text: self.text.clone(),
+            html: None,
+            sub_start: self.sub_start,
+            sub_end: self.sub_end,
+            media_path: Some(self.media_path.clone()),
+            media_title: self.media_title.clone(),
+            aid: self.aid,
+            audio_source: self.audio_source.clone(),
+            sid: self.sid,
+            translation: self.translation.clone(),
+            offsets: self.offsets,
+        }
+    }
+}
+
+/// On-disk record of every captured subtitle. All methods block; call them
+/// from `spawn_blocking`.
+pub struct History {
+    conn: Mutex<Connection>,
+    /// Media path and id of the session lines are currently appended to.
+    session: Mutex<Option<(String, i64)>>,
+}
+
+impl History {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        // Lines are written one at a time as they are captured; with WAL and
+        // without a sync per commit, each insert stays cheap.
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(SCHEMA)?;
+        let has_fts: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = 'lines_fts')",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_fts {
+            debug!("[history] Building search index");
+            conn.execute_batch(FTS_SCHEMA)?;
+        }
+        info!("[history] Recording to {}", path.display());
+        Ok(Self {
+            conn: Mutex::new(conn),
+            session: Mutex::new(None),
+        })
+    }
+
+    /// Id of the new line, or `None` if the subtitle has no media path and
+    /// wasn't recorded.
+    pub fn record(&self, sub: &Subtitle) -> rusqlite::Result<Option<i64>> {
+        let Some(media_path) = &sub.media_path else {
+            debug!("[history] Skipping subtitle {} without media path", sub.id);
+            return Ok(None);
+        };
+        let conn = self.conn.lock().unwrap();
+        let mut session = self.session.lock().unwrap();
+
+        let session_id = match session.as_ref() {
+            Some((path, id)) if path == media_path => *id,
+            _ => {
+                conn.execute(
+                    "INSERT INTO sessions (media_path, media_title, started_at) VALUES (?1, ?2, ?3)",
+                    params![media_path, sub.media_title, unix_now()],
+                )?;
+                let id = conn.last_insert_rowid();
+                debug!("[history] Started session {} for {}", id, media_path);
+                *session = Some((media_path.clone(), id));
+                id
+            }
+        };
+
+        conn.execute(
+            "INSERT INTO lines (session_id, text, sub_start, sub_end, aid, sid, translation,
+                                captured_at, sub_delay, sub_speed, audio_delay,
+                                audio_input, audio_stream)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                session_id,
+                sub.text,
+                sub.sub_start,
+                sub.sub_end,
+                sub.aid,
+                sub.sid,
+                sub.translation,
+                unix_now(),
+                sub.offsets.sub_delay,
+                sub.offsets.sub_speed,
+                sub.offsets.audio_delay,
+                sub.audio_source.as_ref().map(|s| &s.input_path),
+                sub.audio_source.as_ref().map(|s| &s.stream),
+            ],
+        )?;
+        Ok(Some(conn.last_insert_rowid()))
+    }
+
+    /// Most recent sessions first, starting below `before_id` if given.
+    pub fn sessions(&self, before_id: Option<i64>, limit: usize) -> rusqlite::Result<Vec<Session>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT s.id, s.media_path, s.media_title, s.started_at,
+                    (SELECT COUNT(*) FROM lines l WHERE l.session_id = s.id)
+             FROM sessions s
+             WHERE s.id < ?1
+             ORDER BY s.id DESC
+             LIMIT ?2",
+        )?;
+        stmt.query_map(
+            params![before_id.unwrap_or(i64::MAX), limit as i64],
+            |row| {
+                Ok(Session {
+                    id: row.get(0)?,
+                    media_path: row.get(1)?,
+                    media_title: row.get(2)?,
+                    started_at: row.get(3)?,
+                    line_count: row.get(4)?,
+                })
+            },
+        )?
+        .collect()
+    }
+
+    /// Lines of a session in capture order, starting after `after_id`.
+    pub fn lines(
+        &self,
+        session_id: i64,
+        after_id: Option<i64>,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<HistoryLine>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "{} WHERE l.session_id = ?1 AND l.id > ?2 ORDER BY l.id LIMIT ?3",
+            LINE_QUERY
+        ))?;
+        stmt.query_map(
+            params![session_id, after_id.unwrap_or(0), limit as i64],
+            line_from_row,
+        )?
+        .collect()
+    }
+
+    /// Up to `limit` lines matching `query`, newest first. Terms of three or
+    /// more characters are looked up in the trigram index. The rows it
+    /// returns, or all rows if no term was long enough, are then checked
+    /// with `SearchQuery::matches` until `limit` of them match.
+    pub fn search(
+        &self,
+        query: &SearchQuery,
+        media_path: Option<&str>,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<HistoryLine>> {
+        let (sql, fts_match) = search_sql(query);
+        let mut values: Vec<&dyn rusqlite::ToSql> = vec![&media_path];
+        values.extend(fts_match.as_ref().map(|m| m as &dyn rusqlite::ToSql));
+
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&sql)?;
+        stmt.query_map(values.as_slice(), line_from_row)?
+            .filter(|line| line.as_ref().map_or(true, |l| query.matches(&l.text)))
+            .take(limit)
+            .collect()
+    }
+
+    pub fn line(&self, id: i64) -> rusqlite::Result<Option<HistoryLine>> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            &format!("{} WHERE l.id = ?1", LINE_QUERY),
+            params![id],
+            line_from_row,
+        )
+        .optional()
+    }
+}
+
+/// Statement for `History::search`, taking the media path as `?1` and the
+/// FTS5 query, if there is one, as `?2`.
+fn search_sql(query: &SearchQuery) -> (String, Option<String>) {
+    let fts_match = query.fts_match();
+    let mut sql = LINE_QUERY.to_string();
+    if fts_match.is_some() {
+        sql.push_str(" JOIN lines_fts f ON f.rowid = l.id WHERE lines_fts MATCH ?2 AND");
+    } else {
+        sql.push_str(" WHERE");
+    }
+    sql.push_str(" (?1 IS NULL OR s.media_path = ?1) ORDER BY l.id DESC");
+    (sql, fts_match)
+}
+
+const LINE_QUERY: &str = "SELECT l.id, l.session_id, l.text, l.sub_start, l.sub_end, l.aid, l.sid,
+        l.translation, l.captured_at, s.media_path, s.media_title,
+        l.sub_delay, l.sub_speed, l.audio_delay, l.audio_input, l.audio_stream
+ FROM lines l JOIN sessions s ON s.id = l.session_id";
+
+fn line_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<HistoryLine> {
+    let audio_input: Option<String> = row.get(14)?;
+    let audio_stream: Option<String> = row.get(15)?;
+    Ok(HistoryLine {
+        id: row.get(0)?,
+        session_id: row.get(1)?,
+        text: row.get(2)?,
+        sub_start: row.get(3)?,
+        sub_end: row.get(4)?,
+        aid: row.get(5)?,
+        sid: row.get(6)?,
+        translation: row.get(7)?,
+        captured_at: row.get(8)?,
+        media_path: row.get(9)?,
+        media_title: row.get(10)?,
+        offsets: TimingOffsets {
+            sub_delay: row.get(11)?,
+            sub_speed: row.get(12)?,
+            audio_delay: row.get(13)?,
+        },
+        audio_source: audio_input
+            .zip(audio_stream)
+            .map(|(input_path, stream)| TrackSource { input_path, stream }),
+    })
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::SearchMode;
+
+    fn subtitle(text: &str, media_path: Option<&str>) -> Subtitle {
+        Subtitle {
+            id: 1,
+            text: text.to_string(),
+            html: None,
+            sub_start: Some(1.0),
+            sub_end: Some(2.5),
+            media_path: media_path.map(|p| p.to_string()),
+            media_title: None,
+            aid: Some(1),
+            audio_source: Some(TrackSource {
+                input_path: "/a.ja.mka".to_string(),
+                stream: "0:0".to_string(),
+            }),
+            sid: Some(2),
+            translation: Some("Don't give up".to_string()),
+            offsets: TimingOffsets {
+                sub_delay: 0.5,
+                sub_speed: 1.0,
+                audio_delay: -0.25,
+            },
+        }
+    }
+
+    fn open() -> History {
+        History::open(Path::new(":memory:")).unwrap()
+    }
+
+    #[test]
+    fn sessions_split_when_the_file_changes() {
+        let history = open();
+        for path in ["/a.mkv", "/a.mkv", "/b.mkv", "/a.mkv"] {
+            assert!(
+                history
+                    .record(&subtitle("行くぞ", Some(path)))
+                    .unwrap()
+                    .is_some()
+            );
+        }
+        assert_eq!(history.record(&subtitle("行くぞ", None)).unwrap(), None);
+
+        let sessions = history.sessions(None, 10).unwrap();
+        let summary: Vec<_> = sessions
+            .iter()
+            .map(|s| (s.media_path.as_str(), s.line_count))
+            .collect();
+        assert_eq!(summary, [("/a.mkv", 1), ("/b.mkv", 1), ("/a.mkv", 2)]);
+
+        let older = history.sessions(Some(sessions[1].id), 10).unwrap();
+        assert_eq!(older.len(), 1);
+        assert_eq!(older[0].id, sessions[2].id);
+    }
+
+    #[test]
+    fn lines_are_paged_in_capture_order() {
+        let history = open();
+        let ids: Vec<_> = ["一", "二", "三", "四", "五"]
+            .into_iter()
+            .map(|text| {
+                history
+                    .record(&subtitle(text, Some("/a.mkv")))
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect();
+        let session_id = history.sessions(None, 1).unwrap()[0].id;
+
+        let page = |after_id| -> Vec<String> {
+            let lines = history.lines(session_id, after_id, 2).unwrap();
+            lines.into_iter().map(|l| l.text).collect()
+        };
+        assert_eq!(page(None), ["一", "二"]);
+        assert_eq!(page(Some(ids[1])), ["三", "四"]);
+        assert_eq!(page(Some(ids[3])), ["五"]);
+        assert!(page(Some(ids[4])).is_empty());
+    }
+
+    #[test]
+    fn line_keeps_what_media_requests_need() {
+        let history = open();
+        let id = history
+            .record(&subtitle("諦めるな", Some("/a.mkv")))
+            .unwrap()
+            .unwrap();
+
+        let line = history.line(id).unwrap().unwrap();
+        assert_eq!(line.text, "諦めるな");
+        assert_eq!(line.media_path, "/a.mkv");
+        assert_eq!((line.sub_start, line.sub_end), (Some(1.0), Some(2.5)));
+        assert_eq!(line.translation.as_deref(), Some("Don't give up"));
+        assert_eq!(line.offsets.audio_delay, -0.25);
+        let sub = line.to_subtitle();
+        assert_eq!(sub.media_timing(), Some((1.5, 3.0)));
+        assert_eq!(
+            sub.audio_input().map(|a| (a.input_path, a.stream)),
+            Some(("/a.ja.mka".to_string(), "0:0".to_string()))
+        );
+
+        assert!(history.line(id + 1).unwrap().is_none());
+    }
+
+    fn history_with(lines: &[&str]) -> History {
+        let history = History::open(Path::new(":memory:")).unwrap();
+        let conn = history.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO sessions (id, media_path, started_at) VALUES (1, '/a.mkv', 0)",
+            [],
+        )
+        .unwrap();
+        for text in lines {
+            conn.execute(
+                "INSERT INTO lines (session_id, text, captured_at) VALUES (1, ?1, 0)",
+                params![text],
+            )
+            .unwrap();
+        }
+        drop(conn);
+        history
+    }
+
+    fn search(history: &History, query: &str, mode: SearchMode) -> Vec<String> {
+        let query = SearchQuery::new(query, mode);
+        let lines = history.search(&query, None, 10).unwrap();
+        lines.into_iter().map(|l| l.text).collect()
+    }
+
+    #[test]
+    fn search_matches_long_and_short_terms() {
+        let history = history_with(&["諦めるな", "行くぞ", "100% \"Sure\"", "Go on"]);
+
+        assert_eq!(
+            search(&history, "めるな", SearchMode::Substring),
+            ["諦めるな"]
+        );
+        assert_eq!(search(&history, "行く", SearchMode::Substring), ["行くぞ"]);
+        assert_eq!(
+            search(&history, "% \"sure", SearchMode::Substring),
+            ["100% \"Sure\""]
+        );
+        assert_eq!(
+            search(&history, "0%", SearchMode::Substring),
+            ["100% \"Sure\""]
+        );
+        assert_eq!(search(&history, "on GO", SearchMode::Tokens), ["Go on"]);
+        assert!(search(&history, "go sure", SearchMode::Tokens).is_empty());
+    }
+
+    #[test]
+    fn search_folds_case_like_the_store() {
+        let history = history_with(&["ÉTÉ chaud", "Ωμέγα"]);
+
+        assert_eq!(
+            search(&history, "été", SearchMode::Substring),
+            ["ÉTÉ chaud"]
+        );
+        assert_eq!(search(&history, "ét", SearchMode::Substring), ["ÉTÉ chaud"]);
+        assert_eq!(search(&history, "ωμ", SearchMode::Substring), ["Ωμέγα"]);
+    }
+
+    #[test]
+    fn search_uses_the_trigram_index() {
+        let history = history_with(&[]);
+        let (sql, fts_match) = search_sql(&SearchQuery::new("めるな 諦", SearchMode::Tokens));
+        let media_path: Option<&str> = None;
+        let values: [&dyn rusqlite::ToSql; 2] = [&media_path, &fts_match];
+
+        let conn = history.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", sql))
+            .unwrap();
+        let plan: Vec<String> = stmt
+            .query_map(values.as_slice(), |row| row.get(3))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+
+        // FTS5 reports a MATCH lookup as `M` in the index string.
+        assert!(
+            plan.iter()
+                .any(|step| step.starts_with("SCAN f VIRTUAL TABLE INDEX 0:M")),
+            "{:?}",
+            plan
+        );
+        assert!(!plan.iter().any(|step| step == "SCAN l"), "{:?}", plan);
+    }
+}
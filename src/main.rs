@@ -1,13 +1,31 @@
+mod ass;
+mod capture;
 mod event_loop;
+mod export;
+mod history;
 mod media;
+mod media_cache;
+mod mpv_client;
 mod mpv_stream;
+mod playback;
+mod search;
+mod store;
+mod study;
+mod subtitle_track;
+mod track_list;
 
-use clap::Parser;
-use event_loop::run_server;
+use clap::{Parser, Subcommand};
+use event_loop::{ServerConfig, run_server};
+use std::path::PathBuf;
+use std::time::Duration;
+use store::RetentionPolicy;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to the mpv IPC socket
     #[cfg_attr(unix, arg(default_value = "/tmp/mpv-socket"))]
     #[cfg_attr(windows, arg(default_value = r"\\.\pipe\mpv-socket"))]
@@ -24,6 +42,97 @@ struct Args {
     /// Validate that the IPC socket belongs to this mpv PID
     #[arg(long)]
     expected_mpv_pid: Option<u32>,
+
+    /// Number of events buffered per WebSocket client before it is resynced
+    #[arg(long, default_value_t = 64)]
+    broadcast_capacity: usize,
+
+    /// Maximum number of subtitles kept per media file
+    #[arg(long, default_value_t = 5000)]
+    max_subtitles_per_file: usize,
+
+    /// Maximum number of media files whose subtitles are kept
+    #[arg(long, default_value_t = 10)]
+    max_files: usize,
+
+    /// Drop subtitles older than this many seconds
+    #[arg(long)]
+    max_subtitle_age: Option<u64>,
+
+    /// Record every captured subtitle to this SQLite database
+    #[arg(long)]
+    history_db: Option<PathBuf>,
+
+    /// Kill ffmpeg runs that take longer than this many seconds
+    #[arg(long, default_value_t = 60)]
+    ffmpeg_timeout: u64,
+
+    /// Maximum number of ffmpeg processes running at once
+    #[arg(long, default_value_t = 4)]
+    max_ffmpeg_jobs: usize,
+
+    /// Memory in MiB for caching generated thumbnails and audio (0 disables it)
+    #[arg(long, default_value_t = 64)]
+    media_cache_mb: usize,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Export the subtitles captured by a running server
+    Export {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = export::ExportFormat::Srt)]
+        format: export::ExportFormat,
+
+        /// Media file to export (defaults to every file the server holds)
+        #[arg(long)]
+        media_path: Option<String>,
+
+        /// First subtitle id to include
+        #[arg(long)]
+        start_id: Option<u64>,
+
+        /// Last subtitle id to include
+        #[arg(long)]
+        end_id: Option<u64>,
+
+        /// WebSocket port of the running server
+        #[arg(long, default_value_t = 61777)]
+        port: u16,
+
+        /// Write to this file instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
+
+async fn run_command(command: Command) -> std::io::Result<()> {
+    match command {
+        Command::Export {
+            format,
+            media_path,
+            start_id,
+            end_id,
+            port,
+            output,
+        } => {
+            let request = serde_json::json!({
+                "request": "export",
+                "format": format.as_str(),
+                "media_path": media_path,
+                "start_id": start_id,
+                "end_id": end_id,
+            });
+            let data = export::fetch(port, request).await?;
+            match output {
+                Some(path) => std::fs::write(path, data),
+                None => {
+                    print!("{}", data);
+                    Ok(())
+                }
+            }
+        }
+    }
 }
 
 #[tokio::main]
@@ -32,10 +141,40 @@ async fn main() {
 
     let args = Args::parse();
 
+    if let Some(command) = args.command {
+        if let Err(e) = run_command(command).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     media::init_ffmpeg_path(&args.ffmpeg_path);
     log::info!("Using ffmpeg: {}", args.ffmpeg_path);
 
-    if let Err(e) = run_server(&args.socket_path, args.port, args.expected_mpv_pid).await {
+    let history = match args.history_db.as_deref().map(history::History::open) {
+        Some(Ok(history)) => Some(history),
+        Some(Err(e)) => {
+            eprintln!("Error: failed to open history database: {}", e);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+
+    let config = ServerConfig {
+        broadcast_capacity: args.broadcast_capacity,
+        retention: RetentionPolicy {
+            max_entries_per_file: args.max_subtitles_per_file,
+            max_files: args.max_files,
+            max_age: args.max_subtitle_age.map(Duration::from_secs),
+        },
+        history,
+        ffmpeg_timeout: Duration::from_secs(args.ffmpeg_timeout),
+        max_ffmpeg_jobs: args.max_ffmpeg_jobs,
+        media_cache_size: args.media_cache_mb * 1024 * 1024,
+    };
+
+    if let Err(e) = run_server(&args.socket_path, args.port, args.expected_mpv_pid, config).await {
         eprintln!("Error: {}", e);
         std::process::exit(1);
     }
@@ -1,16 +1,44 @@
-use base64::Engine;
 use log::{debug, info, warn};
-use serde::Deserialize;
-use std::process::{Command, Stdio};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::process::{Command, ExitStatus, Stdio};
 use std::sync::OnceLock;
 use std::{env, fs, path::PathBuf};
 use uuid::Uuid;
 
 use crate::event_loop::Subtitle;
+use crate::media_cache::CacheKey;
+use crate::track_list::TrackSource;
 
 const DEFAULT_AUDIO_OFFSET: f64 = 0.25;
 
+/// How much of ffmpeg's stderr is kept when it fails.
+const STDERR_EXCERPT_LINES: usize = 10;
+const STDERR_EXCERPT_CHARS: usize = 1000;
+
 static FFMPEG_PATH: OnceLock<String> = OnceLock::new();
+static FFMPEG_CAPABILITIES: tokio::sync::OnceCell<FfmpegCapabilities> =
+    tokio::sync::OnceCell::const_new();
+
+/// Output formats of `ImageConfig`/`AudioConfig` and the encoder each needs.
+const IMAGE_FORMATS: [(&str, &str); 3] = [
+    ("jpeg", "mjpeg"),
+    ("webp", "libwebp"),
+    ("avif", "libaom-av1"),
+];
+const AUDIO_FORMATS: [(&str, &str); 2] = [("mp3", "libmp3lame"), ("opus", "libopus")];
+
+/// What the configured ffmpeg build can do, as advertised to clients.
+#[derive(Debug, Default, Serialize)]
+pub struct FfmpegCapabilities {
+    /// Whether `ffmpeg -encoders` ran at all.
+    pub available: bool,
+    pub image_formats: Vec<&'static str>,
+    pub audio_formats: Vec<&'static str>,
+    /// Every video and audio encoder, for use in `advanced_args`.
+    pub encoders: Vec<String>,
+    pub https: bool,
+}
 
 pub fn init_ffmpeg_path(path: &str) {
     let resolved = resolve_ffmpeg_path(path);
@@ -20,6 +48,88 @@ pub fn init_ffmpeg_path(path: &str) {
     FFMPEG_PATH.set(resolved).ok();
 }
 
+/// Queries the configured ffmpeg for its encoders and protocols. Blocks on
+/// ffmpeg; see `capabilities`.
+fn detect_capabilities() -> FfmpegCapabilities {
+    let encoders = ffmpeg_listing("-encoders").map(|out| {
+        out.lines()
+            .skip_while(|l| !l.trim_start().starts_with("---"))
+            .skip(1)
+            .filter_map(|l| {
+                let mut fields = l.split_whitespace();
+                let flags = fields.next()?;
+                let name = fields.next()?;
+                flags.starts_with(['V', 'A']).then(|| name.to_string())
+            })
+            .collect::<Vec<_>>()
+    });
+    let https = ffmpeg_listing("-protocols").is_some_and(|out| {
+        out.lines()
+            .skip_while(|l| l.trim() != "Input:")
+            .take_while(|l| l.trim() != "Output:")
+            .any(|l| l.trim() == "https")
+    });
+
+    let caps = match encoders {
+        Some(encoders) => {
+            let supported = |formats: &[(&'static str, &str)]| {
+                formats
+                    .iter()
+                    .filter(|(_, encoder)| encoders.iter().any(|e| e == encoder))
+                    .map(|(format, _)| *format)
+                    .collect()
+            };
+            FfmpegCapabilities {
+                available: true,
+                image_formats: supported(&IMAGE_FORMATS),
+                audio_formats: supported(&AUDIO_FORMATS),
+                encoders,
+                https,
+            }
+        }
+        None => FfmpegCapabilities::default(),
+    };
+    info!(
+        "[media] ffmpeg image formats: [{}], audio formats: [{}], https: {}",
+        caps.image_formats.join(", "),
+        caps.audio_formats.join(", "),
+        caps.https
+    );
+    caps
+}
+
+/// Probes ffmpeg on first use, off the async runtime, so a slow or missing
+/// ffmpeg doesn't hold up startup. Concurrent callers share the one probe.
+pub async fn capabilities() -> &'static FfmpegCapabilities {
+    FFMPEG_CAPABILITIES
+        .get_or_init(|| async {
+            tokio::task::spawn_blocking(detect_capabilities)
+                .await
+                .unwrap_or_default()
+        })
+        .await
+}
+
+/// Stdout of `ffmpeg -hide_banner <flag>`, or `None` if ffmpeg can't run.
+fn ffmpeg_listing(flag: &str) -> Option<String> {
+    let out = Command::new(ffmpeg())
+        .args(["-hide_banner", flag])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    match out {
+        Ok(out) if out.status.success() => Some(String::from_utf8_lossy(&out.stdout).into_owned()),
+        Ok(out) => {
+            warn!("[media] ffmpeg {} failed ({})", flag, out.status);
+            None
+        }
+        Err(e) => {
+            warn!("[media] ffmpeg failed to start: {}", e);
+            None
+        }
+    }
+}
+
 fn ffmpeg() -> &'static str {
     FFMPEG_PATH.get().map(|s| s.as_str()).unwrap_or("ffmpeg")
 }
@@ -52,7 +162,56 @@ fn temp_path(prefix: &str, ext: &str) -> PathBuf {
     env::temp_dir().join(format!("{}_{}.{}", prefix, Uuid::new_v4(), ext))
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Temp file deleted when dropped, so it's cleaned up however the request
+/// ends, including when a timed out or cancelled `run` is dropped.
+#[derive(Debug)]
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Where ffmpeg writes its result.
+#[derive(Debug)]
+enum Output {
+    /// ffmpeg's stdout.
+    Pipe,
+    /// For muxers that seek back to finish their headers, which a pipe
+    /// doesn't allow.
+    File(TempFile),
+}
+
+impl Output {
+    /// Appends the output arguments. Pipes when `pipe_format` names a muxer
+    /// that can write to a pipe, else writes to a temp file named after
+    /// `prefix` and `ext`.
+    fn new(args: &mut Vec<String>, pipe_format: Option<&str>, prefix: &str, ext: &str) -> Self {
+        match pipe_format {
+            Some(format) => {
+                args.extend(["-f".into(), format.to_string(), "pipe:1".into()]);
+                Output::Pipe
+            }
+            None => {
+                let path = temp_path(prefix, ext);
+                args.extend(["-y".into(), path.display().to_string()]);
+                Output::File(TempFile(path))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Output::Pipe => write!(f, "stdout"),
+            Output::File(file) => write!(f, "{}", file.0.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ImageConfig {
     pub format: String,
@@ -88,10 +247,20 @@ impl ImageConfig {
         }
     }
 
-    pub fn apply_to_args(&self, args: &mut Vec<String>, sub: &Subtitle) {
+    /// Muxer to pipe the image through, if it can be. AVIF and animated
+    /// WebP need a seekable file.
+    fn pipe_format(&self) -> Option<&'static str> {
+        match self.get_extension() {
+            "jpg" | "png" => Some("image2pipe"),
+            "webp" if !self.is_animated => Some("image2pipe"),
+            _ => None,
+        }
+    }
+
+    pub fn apply_to_args(&self, args: &mut Vec<String>, duration: f64) {
         if let Some(advanced) = &self.advanced_args {
             if self.is_animated {
-                args.extend(["-t".into(), format!("{:.3}", sub.sub_end - sub.sub_start)]);
+                args.extend(["-t".into(), format!("{:.3}", duration)]);
             } else {
                 args.extend(["-vframes".into(), "1".into()]);
             }
@@ -100,15 +269,15 @@ impl ImageConfig {
         }
 
         if self.is_animated {
-            args.extend(["-t".into(), format!("{:.3}", sub.sub_end - sub.sub_start)]);
+            args.extend(["-t".into(), format!("{:.3}", duration)]);
         } else {
             args.extend(["-vframes".into(), "1".into()]);
         }
 
-        if let Some(size) = &self.size {
-            if !size.trim().is_empty() {
-                args.extend(["-vf".into(), format!("scale={}", size)]);
-            }
+        if let Some(size) = &self.size
+            && !size.trim().is_empty()
+        {
+            args.extend(["-vf".into(), format!("scale={}", size)]);
         }
 
         match self.format.as_str() {
@@ -150,7 +319,7 @@ impl ImageConfig {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct AudioConfig {
     pub format: String,
@@ -183,6 +352,15 @@ impl AudioConfig {
         }
     }
 
+    /// Muxer to pipe the audio through, if it can be.
+    fn pipe_format(&self) -> Option<&'static str> {
+        match self.get_extension() {
+            "mp3" => Some("mp3"),
+            "opus" | "ogg" => Some("ogg"),
+            _ => None,
+        }
+    }
+
     pub fn apply_to_args(&self, args: &mut Vec<String>) {
         if let Some(advanced) = &self.advanced_args {
             args.extend(advanced.split_whitespace().map(|s| s.to_string()));
@@ -206,161 +384,260 @@ impl AudioConfig {
         }
 
         let mut filters = vec!["afade=t=in:d=0.005".to_string()];
-        if let Some(f) = &self.filters {
-            if !f.trim().is_empty() {
-                filters.push(f.clone());
-            }
+        if let Some(f) = &self.filters
+            && !f.trim().is_empty()
+        {
+            filters.push(f.clone());
         }
         args.extend(["-af".into(), filters.join(",")]);
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
+pub enum FfmpegError {
+    Spawn(std::io::Error),
+    /// Non-zero exit, with the last lines of stderr.
+    Failed {
+        status: ExitStatus,
+        stderr: String,
+    },
+    EmptyOutput,
+}
+
+impl fmt::Display for FfmpegError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FfmpegError::Spawn(e) => write!(f, "ffmpeg failed to start: {}", e),
+            FfmpegError::Failed { status, stderr } => {
+                write!(f, "ffmpeg failed ({}): {}", status, stderr)
+            }
+            FfmpegError::EmptyOutput => write!(f, "ffmpeg produced no output"),
+        }
+    }
+}
+
+impl std::error::Error for FfmpegError {}
+
+/// Last lines of `stderr` joined with ` | `, keeping at most the final
+/// `STDERR_EXCERPT_CHARS` characters.
+fn stderr_excerpt(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines: Vec<_> = stderr
+        .lines()
+        .rev()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .take(STDERR_EXCERPT_LINES)
+        .collect();
+    let excerpt = lines.into_iter().rev().collect::<Vec<_>>().join(" | ");
+    match excerpt.char_indices().rev().nth(STDERR_EXCERPT_CHARS - 1) {
+        Some((start, _)) => format!("...{}", &excerpt[start..]),
+        None => excerpt,
+    }
+}
+
+#[derive(Debug)]
 pub struct FfmpegRequest {
-    output_path: PathBuf,
+    output: Output,
     args: Vec<String>,
+    /// `None` for results that aren't worth caching.
+    cache_key: Option<CacheKey>,
 }
 
 impl FfmpegRequest {
-    pub fn thumbnail(sub: &Subtitle, config: Option<ImageConfig>) -> Self {
+    /// Returns `None` if the subtitle has no timing or media path.
+    pub fn thumbnail(sub: &Subtitle, config: Option<ImageConfig>) -> Option<Self> {
+        let (sub_start, sub_end) = sub.media_timing()?;
+        let media_path = sub.media_path.as_deref()?;
         let config = config.unwrap_or_default();
         let is_animated = config.is_animated;
 
-        let ext = config.get_extension();
-        let output = temp_path("thumb", ext);
-        let mid_time = (sub.sub_start + sub.sub_end) / 2.0;
+        let mid_time = (sub_start + sub_end) / 2.0;
 
         debug!(
             "[media] Thumbnail ({}) at {:.3} from {}",
-            config.format, mid_time, sub.media_path
+            config.format, mid_time, media_path
         );
 
-        let ss = if is_animated { sub.sub_start } else { mid_time };
+        let ss = if is_animated { sub_start } else { mid_time };
+        let duration = if is_animated {
+            sub_end - sub_start
+        } else {
+            0.0
+        };
+        let cache_key = CacheKey::new("thumbnail", media_path, ss, duration, "", &config);
 
         let mut args = vec![
             "-ss".into(),
             format!("{:.3}", ss),
             "-i".into(),
-            sub.media_path.clone(),
+            media_path.to_string(),
         ];
 
-        config.apply_to_args(&mut args, sub);
+        config.apply_to_args(&mut args, sub_end - sub_start);
 
-        args.extend(["-y".into(), output.display().to_string()]);
-        Self {
+        let output = Output::new(
+            &mut args,
+            config.pipe_format(),
+            "thumb",
+            config.get_extension(),
+        );
+        Some(Self {
             args,
-            output_path: output,
-        }
+            output,
+            cache_key: Some(cache_key),
+        })
     }
 
+    /// Returns `None` if the subtitle has no timing, media path or audio
+    /// track.
     pub fn audio(
         sub: &Subtitle,
         offset_start: Option<f64>,
         offset_end: Option<f64>,
         config: Option<AudioConfig>,
-    ) -> Self {
-        Self::audio_range(
-            sub.sub_start,
-            sub.sub_end,
-            &sub.media_path,
-            sub.aid,
+    ) -> Option<Self> {
+        let (audio_start, audio_end) = sub.audio_timing()?;
+        Some(Self::audio_range(
+            audio_start,
+            audio_end,
+            &sub.audio_input()?,
             offset_start,
             offset_end,
             config,
-        )
+        ))
     }
 
+    /// `audio_start`/`audio_end` are positions in the audio stream, see
+    /// `Subtitle::audio_timing`.
     pub fn audio_range(
-        sub_start: f64,
-        sub_end: f64,
-        media_path: &str,
-        aid: i64,
+        audio_start: f64,
+        audio_end: f64,
+        source: &TrackSource,
         offset_start: Option<f64>,
         offset_end: Option<f64>,
         config: Option<AudioConfig>,
     ) -> Self {
         let config = config.unwrap_or_default();
-        let ext = config.get_extension();
-        let output = temp_path("audio", ext);
         let start_offset = offset_start.unwrap_or(DEFAULT_AUDIO_OFFSET);
         let end_offset = offset_end.unwrap_or(DEFAULT_AUDIO_OFFSET);
-        let start = (sub_start - start_offset).max(0.0);
-        let duration = sub_end - sub_start + start_offset + end_offset;
+        let start = (audio_start - start_offset).max(0.0);
+        let duration = audio_end - audio_start + start_offset + end_offset;
+        let cache_key = CacheKey::new(
+            "audio",
+            &source.input_path,
+            start,
+            duration,
+            &source.stream,
+            &config,
+        );
 
         debug!(
             "[media] Audio ({}) {:.3}-{:.3} from {}",
             config.format,
             start,
             start + duration,
-            media_path
+            source.input_path
         );
 
         let mut args = vec![
             "-ss".into(),
             format!("{:.3}", start),
             "-i".into(),
-            media_path.to_string(),
+            source.input_path.clone(),
             "-t".into(),
             format!("{:.3}", duration),
             "-map".into(),
-            format!("0:a:{}", (aid - 1).max(0)),
+            source.stream.clone(),
             "-vn".into(),
         ];
 
         config.apply_to_args(&mut args);
 
-        args.extend(["-y".into(), output.display().to_string()]);
+        let output = Output::new(
+            &mut args,
+            config.pipe_format(),
+            "audio",
+            config.get_extension(),
+        );
+        Self {
+            args,
+            output,
+            cache_key: Some(cache_key),
+        }
+    }
+
+    /// Converts one subtitle stream to SRT. `stream` is an ffmpeg `-map`
+    /// specifier such as `0:3`.
+    pub fn subtitle_track(input_path: &str, stream: &str) -> Self {
+        debug!("[media] Subtitle track {} from {}", stream, input_path);
+
+        let mut args = vec![
+            "-i".into(),
+            input_path.to_string(),
+            "-map".into(),
+            stream.to_string(),
+        ];
+        let output = Output::new(&mut args, Some("srt"), "track", "srt");
 
         Self {
             args,
-            output_path: output,
+            output,
+            cache_key: None,
         }
     }
 
-    pub fn execute(self) -> Option<String> {
+    pub fn cache_key(&self) -> Option<&CacheKey> {
+        self.cache_key.as_ref()
+    }
+
+    /// Runs ffmpeg and returns the raw output.
+    pub async fn run(self) -> Result<Vec<u8>, FfmpegError> {
         info!("[media] Running: {} {}", ffmpeg(), self.args.join(" "));
 
-        let result = Command::new(ffmpeg())
+        // Dropping the future (timeout, cancelled request, disconnected
+        // client) kills ffmpeg.
+        let result = tokio::process::Command::new(ffmpeg())
             .args(&self.args)
             .stdin(Stdio::null())
-            .stdout(Stdio::null())
+            .stdout(match self.output {
+                Output::Pipe => Stdio::piped(),
+                Output::File(_) => Stdio::null(),
+            })
             .stderr(Stdio::piped())
-            .output();
-
-        let cleanup = || {
-            let _ = fs::remove_file(&self.output_path);
-        };
+            .kill_on_drop(true)
+            .output()
+            .await;
 
         match result {
-            Ok(out) if out.status.success() => match fs::read(&self.output_path) {
-                Ok(data) if !data.is_empty() => {
-                    cleanup();
-                    Some(base64::engine::general_purpose::STANDARD.encode(&data))
+            Ok(out) if out.status.success() => {
+                let data = match &self.output {
+                    Output::Pipe => Ok(out.stdout),
+                    Output::File(file) => tokio::fs::read(&file.0).await,
+                };
+                match data {
+                    Ok(data) if !data.is_empty() => Ok(data),
+                    _ => {
+                        warn!(
+                            "[media] ffmpeg succeeded but output is empty or missing: {}",
+                            self.output
+                        );
+                        Err(FfmpegError::EmptyOutput)
+                    }
                 }
-                _ => {
-                    warn!(
-                        "[media] ffmpeg succeeded but output file is empty or missing: {}",
-                        self.output_path.display()
-                    );
-                    cleanup();
-                    None
-                }
-            },
+            }
             Ok(out) => {
-                let stderr = String::from_utf8_lossy(&out.stderr);
-                let errors: Vec<_> = stderr.lines().rev().take(10).collect();
-                warn!(
-                    "[media] ffmpeg failed ({}): {}",
-                    out.status,
-                    errors.into_iter().rev().collect::<Vec<_>>().join(" | ")
-                );
-                cleanup();
-                None
+                let err = FfmpegError::Failed {
+                    status: out.status,
+                    stderr: stderr_excerpt(&out.stderr),
+                };
+                warn!("[media] {}", err);
+                Err(err)
             }
             Err(e) => {
-                warn!("[media] ffmpeg failed to start: {}", e);
-                cleanup();
-                None
+                let err = FfmpegError::Spawn(e);
+                warn!("[media] {}", err);
+                Err(err)
             }
         }
     }
@@ -0,0 +1,202 @@
+//! Recently generated thumbnails and audio clips, so asking for the same
+//! media again doesn't rerun ffmpeg.
+
+use log::debug;
+use lru::LruCache;
+use serde::Serialize;
+use std::time::SystemTime;
+
+/// Identifies one ffmpeg result. Times are kept in milliseconds so the key
+/// can be hashed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    kind: &'static str,
+    input_path: String,
+    start_ms: i64,
+    duration_ms: i64,
+    /// ffmpeg `-map` specifier, empty for the default video stream.
+    stream: String,
+    /// `ImageConfig`/`AudioConfig` as JSON.
+    config: String,
+}
+
+impl CacheKey {
+    pub fn new(
+        kind: &'static str,
+        input_path: &str,
+        start: f64,
+        duration: f64,
+        stream: &str,
+        config: &impl Serialize,
+    ) -> Self {
+        Self {
+            kind,
+            input_path: input_path.to_string(),
+            start_ms: (start * 1000.0).round() as i64,
+            duration_ms: (duration * 1000.0).round() as i64,
+            stream: stream.to_string(),
+            config: serde_json::to_string(config).unwrap_or_default(),
+        }
+    }
+
+    /// Modification time of the input, to notice when the file is replaced.
+    /// `None` for network streams and files that can't be read. Taken
+    /// before locking the cache, so a slow filesystem doesn't hold it up.
+    pub async fn input_modified(&self) -> Option<SystemTime> {
+        if self.input_path.contains("://") {
+            return None;
+        }
+        tokio::fs::metadata(&self.input_path)
+            .await
+            .and_then(|m| m.modified())
+            .ok()
+    }
+}
+
+struct CacheEntry {
+    data: Vec<u8>,
+    input_modified: Option<SystemTime>,
+}
+
+/// Least recently used results are dropped once their total size exceeds
+/// the memory budget.
+pub struct MediaCache {
+    entries: LruCache<CacheKey, CacheEntry>,
+    budget: usize,
+    size: usize,
+}
+
+impl MediaCache {
+    /// A `budget` of 0 disables caching.
+    pub fn new(budget: usize) -> Self {
+        Self {
+            entries: LruCache::unbounded(),
+            budget,
+            size: 0,
+        }
+    }
+
+    /// `input_modified` is the input's current `CacheKey::input_modified`.
+    pub fn get(&mut self, key: &CacheKey, input_modified: Option<SystemTime>) -> Option<Vec<u8>> {
+        if !self.contains(key, input_modified) {
+            debug!("[cache] Miss: {} {}", key.kind, key.input_path);
+            return None;
+        }
+        let entry = self.entries.get(key)?;
+        debug!(
+            "[cache] Hit: {} {} ({} bytes)",
+            key.kind,
+            key.input_path,
+            entry.data.len()
+        );
+        Some(entry.data.clone())
+    }
+
+    /// Like `get`, dropping the entry if the input changed, but without
+    /// touching its recency or copying it.
+    pub fn contains(&mut self, key: &CacheKey, input_modified: Option<SystemTime>) -> bool {
+        let Some(entry) = self.entries.peek(key) else {
+            return false;
+        };
+        if entry.input_modified != input_modified {
+            debug!("[cache] {} changed, dropping {}", key.input_path, key.kind);
+            self.remove(key);
+            return false;
+        }
+        true
+    }
+
+    /// `input_modified` is the input's `CacheKey::input_modified` from
+    /// before `data` was generated.
+    pub fn insert(&mut self, key: CacheKey, data: Vec<u8>, input_modified: Option<SystemTime>) {
+        if data.len() > self.budget {
+            return;
+        }
+        self.size += data.len();
+        if let Some((_, old)) = self.entries.push(
+            key,
+            CacheEntry {
+                data,
+                input_modified,
+            },
+        ) {
+            self.size -= old.data.len();
+        }
+        while self.size > self.budget {
+            let Some((key, evicted)) = self.entries.pop_lru() else {
+                break;
+            };
+            debug!("[cache] Evicted {} {}", key.kind, key.input_path);
+            self.size -= evicted.data.len();
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.pop(key) {
+            self.size -= entry.data.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn key(start: f64) -> CacheKey {
+        CacheKey::new("audio", "/a.mkv", start, 1.0, "", &"mp3")
+    }
+
+    #[test]
+    fn least_recently_used_entries_go_over_budget() {
+        let mut cache = MediaCache::new(10);
+        cache.insert(key(1.0), vec![0; 4], None);
+        cache.insert(key(2.0), vec![0; 4], None);
+        assert!(cache.get(&key(1.0), None).is_some());
+        cache.insert(key(3.0), vec![0; 4], None);
+
+        assert!(cache.contains(&key(1.0), None));
+        assert!(!cache.contains(&key(2.0), None));
+        assert!(cache.contains(&key(3.0), None));
+        assert_eq!(cache.size, 8);
+    }
+
+    #[test]
+    fn entries_larger_than_the_budget_are_not_kept() {
+        let mut cache = MediaCache::new(10);
+        cache.insert(key(1.0), vec![0; 4], None);
+        cache.insert(key(2.0), vec![0; 11], None);
+
+        assert!(cache.contains(&key(1.0), None));
+        assert!(!cache.contains(&key(2.0), None));
+        assert_eq!(cache.size, 4);
+    }
+
+    #[test]
+    fn entries_of_a_changed_input_are_dropped() {
+        let mut cache = MediaCache::new(10);
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        cache.insert(key(1.0), vec![1, 2], Some(modified));
+
+        assert_eq!(cache.get(&key(1.0), Some(modified)), Some(vec![1, 2]));
+        assert!(cache.contains(&key(1.0), Some(modified)));
+        let later = modified + Duration::from_secs(1);
+        assert_eq!(cache.get(&key(1.0), Some(later)), None);
+        assert!(!cache.contains(&key(1.0), None));
+        assert_eq!(cache.size, 0);
+
+        cache.insert(key(1.0), vec![1, 2], Some(modified));
+        assert!(!cache.contains(&key(1.0), Some(later)));
+        assert_eq!(cache.size, 0);
+    }
+
+    #[test]
+    fn keys_are_compared_to_the_millisecond() {
+        assert_eq!(key(1.0), key(1.0004));
+        assert_ne!(key(1.0), key(1.001));
+        assert_ne!(
+            key(1.0),
+            CacheKey::new("audio", "/a.mkv", 1.0, 1.0, "", &"opus")
+        );
+    }
+}
@@ -0,0 +1,454 @@
+use log::{debug, info, warn};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{Duration, timeout};
+
+use crate::mpv_stream::MpvStream;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum MpvError {
+    /// mpv answered with an error status, e.g. "property unavailable".
+    Command(String),
+    /// The reply could not be converted to the requested type.
+    InvalidData(String),
+    Timeout,
+    Disconnected,
+}
+
+impl std::fmt::Display for MpvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MpvError::Command(status) => write!(f, "mpv returned error: {}", status),
+            MpvError::InvalidData(msg) => write!(f, "unexpected mpv reply: {}", msg),
+            MpvError::Timeout => write!(f, "timed out waiting for mpv"),
+            MpvError::Disconnected => write!(f, "mpv IPC disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for MpvError {}
+
+impl From<MpvError> for std::io::Error {
+    fn from(e: MpvError) -> Self {
+        let kind = match e {
+            MpvError::Timeout => std::io::ErrorKind::TimedOut,
+            MpvError::Disconnected => std::io::ErrorKind::NotConnected,
+            MpvError::InvalidData(_) => std::io::ErrorKind::InvalidData,
+            MpvError::Command(_) => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, e.to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MpvEvent {
+    /// `data` is `Null` when the property is unavailable.
+    PropertyChange {
+        name: String,
+        data: Value,
+    },
+    /// Any other mpv event; `data` is the full event object.
+    Event {
+        name: String,
+        data: Value,
+    },
+    /// Produced by `MpvEvents`, never lost to lag.
+    Disconnected,
+    Reconnected,
+}
+
+impl MpvEvent {
+    /// Parses an IPC line that carries an `event` field; replies to requests
+    /// yield `None`.
+    pub fn parse(json: &Value) -> Option<Self> {
+        let event = json.get("event").and_then(|e| e.as_str())?;
+        Some(if event == "property-change" {
+            MpvEvent::PropertyChange {
+                name: json
+                    .get("name")
+                    .and_then(|n| n.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                data: json.get("data").cloned().unwrap_or(Value::Null),
+            }
+        } else {
+            MpvEvent::Event {
+                name: event.to_string(),
+                data: json.clone(),
+            }
+        })
+    }
+}
+
+type Reply = Result<Value, MpvError>;
+
+/// State of the IPC connection. Steps only move forward: connected,
+/// disconnected, connected again with `reconnects` incremented, and so on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Connection {
+    connected: bool,
+    reconnects: u64,
+}
+
+impl Connection {
+    fn step(self) -> u64 {
+        self.reconnects * 2 + u64::from(!self.connected)
+    }
+}
+
+#[derive(Clone)]
+enum Broadcast {
+    Event(MpvEvent),
+    Connection(Connection),
+}
+
+struct Inner {
+    outgoing: mpsc::UnboundedSender<String>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Reply>>>,
+    observed: Mutex<HashMap<u64, String>>,
+    events: broadcast::Sender<Broadcast>,
+    /// Held while a connection change is broadcast, so a new subscriber
+    /// starts either before or after it.
+    connection: Mutex<Connection>,
+    next_id: AtomicU64,
+}
+
+/// Events of one subscriber. Property changes and other mpv events are
+/// dropped when the subscriber lags behind; `Disconnected`/`Reconnected`
+/// are not, since after a lag the connection state is compared with the
+/// last one seen and any missed transition is replayed.
+pub struct MpvEvents {
+    rx: broadcast::Receiver<Broadcast>,
+    inner: Arc<Inner>,
+    seen: Connection,
+    missed: VecDeque<MpvEvent>,
+}
+
+impl MpvEvents {
+    pub async fn recv(&mut self) -> Result<MpvEvent, RecvError> {
+        loop {
+            if let Some(event) = self.missed.pop_front() {
+                return Ok(event);
+            }
+            match self.rx.recv().await {
+                Ok(Broadcast::Event(event)) => return Ok(event),
+                Ok(Broadcast::Connection(to)) => self.catch_up(to),
+                Err(RecvError::Lagged(n)) => {
+                    let now = *self.inner.connection.lock().unwrap();
+                    self.catch_up(now);
+                    return Err(RecvError::Lagged(n));
+                }
+                Err(RecvError::Closed) => return Err(RecvError::Closed),
+            }
+        }
+    }
+
+    /// Queues the events leading from `seen` to `to`. Changes already
+    /// replayed after a lag are ignored when they arrive late.
+    fn catch_up(&mut self, to: Connection) {
+        if to.step() <= self.seen.step() {
+            return;
+        }
+        if self.seen.connected {
+            self.missed.push_back(MpvEvent::Disconnected);
+        }
+        if to.connected {
+            self.missed.push_back(MpvEvent::Reconnected);
+        }
+        self.seen = to;
+    }
+}
+
+/// Multiplexed mpv IPC client. Cheap to clone; all clones share one
+/// connection, whose reader task routes replies back to the issuing request
+/// and fans events out to subscribers.
+#[derive(Clone)]
+pub struct MpvClient {
+    inner: Arc<Inner>,
+}
+
+impl MpvClient {
+    pub fn new(stream: MpvStream) -> Self {
+        let (inner, outgoing_rx) = Inner::new();
+        tokio::spawn(run(stream, outgoing_rx, inner.clone()));
+        Self { inner }
+    }
+
+    pub fn subscribe(&self) -> MpvEvents {
+        self.inner.subscribe()
+    }
+
+    pub async fn command(&self, args: &[Value]) -> Result<Value, MpvError> {
+        let request_id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.inner.pending.lock().unwrap().insert(request_id, tx);
+
+        let line = format!(
+            "{}\n",
+            serde_json::json!({ "command": args, "request_id": request_id })
+        );
+        if self.inner.outgoing.send(line).is_err() {
+            self.inner.pending.lock().unwrap().remove(&request_id);
+            return Err(MpvError::Disconnected);
+        }
+
+        match timeout(REQUEST_TIMEOUT, rx).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) => Err(MpvError::Disconnected),
+            Err(_) => {
+                self.inner.pending.lock().unwrap().remove(&request_id);
+                Err(MpvError::Timeout)
+            }
+        }
+    }
+
+    pub async fn get_property<T: DeserializeOwned>(&self, name: &str) -> Result<T, MpvError> {
+        let data = self.command(&["get_property".into(), name.into()]).await?;
+        serde_json::from_value(data).map_err(|e| MpvError::InvalidData(format!("{}: {}", name, e)))
+    }
+
+    pub async fn set_property<T: Serialize>(&self, name: &str, value: T) -> Result<(), MpvError> {
+        let value =
+            serde_json::to_value(value).map_err(|e| MpvError::InvalidData(e.to_string()))?;
+        self.command(&["set_property".into(), name.into(), value])
+            .await
+            .map(|_| ())
+    }
+
+    /// Observes `name` for the lifetime of the client. Observations are
+    /// re-issued automatically after a reconnect.
+    pub async fn observe(&self, name: &str) -> Result<u64, MpvError> {
+        let observe_id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .observed
+            .lock()
+            .unwrap()
+            .insert(observe_id, name.to_string());
+
+        let result = self
+            .command(&["observe_property".into(), observe_id.into(), name.into()])
+            .await;
+        if result.is_err() {
+            self.inner.observed.lock().unwrap().remove(&observe_id);
+        }
+        result.map(|_| observe_id)
+    }
+}
+
+impl Inner {
+    fn new() -> (Arc<Self>, mpsc::UnboundedReceiver<String>) {
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(256);
+        let inner = Arc::new(Inner {
+            outgoing,
+            pending: Mutex::new(HashMap::new()),
+            observed: Mutex::new(HashMap::new()),
+            events,
+            connection: Mutex::new(Connection {
+                connected: true,
+                reconnects: 0,
+            }),
+            next_id: AtomicU64::new(1),
+        });
+        (inner, outgoing_rx)
+    }
+
+    fn subscribe(self: &Arc<Self>) -> MpvEvents {
+        let connection = self.connection.lock().unwrap();
+        MpvEvents {
+            rx: self.events.subscribe(),
+            inner: self.clone(),
+            seen: *connection,
+            missed: VecDeque::new(),
+        }
+    }
+
+    fn set_connected(&self, connected: bool) {
+        let mut connection = self.connection.lock().unwrap();
+        if connected {
+            connection.reconnects += 1;
+        }
+        connection.connected = connected;
+        let _ = self.events.send(Broadcast::Connection(*connection));
+    }
+
+    fn dispatch(&self, line: &str) {
+        let Ok(json) = serde_json::from_str::<Value>(line) else {
+            return;
+        };
+
+        if let Some(event) = MpvEvent::parse(&json) {
+            let _ = self.events.send(Broadcast::Event(event));
+            return;
+        }
+
+        let Some(request_id) = json.get("request_id").and_then(|r| r.as_u64()) else {
+            return;
+        };
+        let Some(tx) = self.pending.lock().unwrap().remove(&request_id) else {
+            return;
+        };
+        let status = json
+            .get("error")
+            .and_then(|e| e.as_str())
+            .unwrap_or("success");
+        let reply = if status == "success" {
+            Ok(json.get("data").cloned().unwrap_or(Value::Null))
+        } else {
+            Err(MpvError::Command(status.to_string()))
+        };
+        let _ = tx.send(reply);
+    }
+
+    fn fail_pending(&self) {
+        for (_, tx) in self.pending.lock().unwrap().drain() {
+            let _ = tx.send(Err(MpvError::Disconnected));
+        }
+    }
+}
+
+async fn run(
+    mut stream: MpvStream,
+    mut outgoing: mpsc::UnboundedReceiver<String>,
+    inner: Arc<Inner>,
+) {
+    loop {
+        match pump(&mut stream, &mut outgoing, &inner).await {
+            Ok(()) => warn!("[mpv] IPC connection closed, reconnecting..."),
+            Err(e) => warn!("[mpv] IPC connection lost ({}), reconnecting...", e),
+        }
+
+        // Drain first: a command queued before this point then still fails
+        // right away instead of waiting out its timeout.
+        while outgoing.try_recv().is_ok() {}
+        inner.fail_pending();
+        inner.set_connected(false);
+
+        stream.reconnect().await;
+
+        let observed: Vec<_> = inner
+            .observed
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, name)| (*id, name.clone()))
+            .collect();
+        for (observe_id, name) in observed {
+            let cmd = format!(
+                "{}\n",
+                serde_json::json!({ "command": ["observe_property", observe_id, name] })
+            );
+            if let Err(e) = stream.write_all(cmd.as_bytes()).await {
+                debug!("[mpv] Failed to re-observe '{}': {}", name, e);
+            }
+        }
+
+        info!("[mpv] Reconnected");
+        inner.set_connected(true);
+    }
+}
+
+async fn pump(
+    stream: &mut MpvStream,
+    outgoing: &mut mpsc::UnboundedReceiver<String>,
+    inner: &Inner,
+) -> std::io::Result<()> {
+    loop {
+        tokio::select! {
+            line = stream.next_line() => {
+                let Some(line) = line? else {
+                    return Ok(()); // EOF
+                };
+                inner.dispatch(&line);
+            }
+            Some(cmd) = outgoing.recv() => {
+                stream.write_all(cmd.as_bytes()).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(inner: &Inner, request_id: u64) -> oneshot::Receiver<Reply> {
+        let (tx, rx) = oneshot::channel();
+        inner.pending.lock().unwrap().insert(request_id, tx);
+        rx
+    }
+
+    #[test]
+    fn replies_go_to_the_request_with_their_id() {
+        let (inner, _outgoing) = Inner::new();
+        let mut first = pending(&inner, 1);
+        let mut second = pending(&inner, 2);
+
+        inner.dispatch(r#"{"request_id":2,"error":"success","data":12.5}"#);
+        inner.dispatch(r#"{"request_id":7,"error":"success","data":0}"#);
+        inner.dispatch(r#"{"request_id":1,"error":"property unavailable"}"#);
+
+        assert_eq!(second.try_recv().unwrap().unwrap(), 12.5);
+        assert!(matches!(
+            first.try_recv().unwrap(),
+            Err(MpvError::Command(status)) if status == "property unavailable"
+        ));
+        assert!(inner.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn property_changes_go_to_subscribers() {
+        let (inner, _outgoing) = Inner::new();
+        let mut events = inner.subscribe();
+        let mut pending = pending(&inner, 3);
+
+        inner.dispatch(r#"{"event":"property-change","id":3,"name":"sub-text","data":"行くぞ"}"#);
+        inner.dispatch(r#"{"event":"property-change","id":4,"name":"sub-start"}"#);
+
+        assert!(matches!(
+            events.recv().await,
+            Ok(MpvEvent::PropertyChange { name, data }) if name == "sub-text" && data == "行くぞ"
+        ));
+        assert!(matches!(
+            events.recv().await,
+            Ok(MpvEvent::PropertyChange { name, data }) if name == "sub-start" && data.is_null()
+        ));
+        assert!(pending.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn reconnects_survive_lag() {
+        let (inner, _outgoing) = Inner::new();
+        let mut events = inner.subscribe();
+        for _ in 0..300 {
+            inner.dispatch(r#"{"event":"property-change","id":1,"name":"time-pos","data":1.0}"#);
+        }
+        inner.set_connected(false);
+        inner.set_connected(true);
+        inner.dispatch(r#"{"event":"seek"}"#);
+
+        assert!(matches!(events.recv().await, Err(RecvError::Lagged(_))));
+        assert!(matches!(events.recv().await, Ok(MpvEvent::Disconnected)));
+        assert!(matches!(events.recv().await, Ok(MpvEvent::Reconnected)));
+
+        // The changes still buffered were already replayed and are skipped.
+        loop {
+            match events.recv().await.unwrap() {
+                MpvEvent::PropertyChange { .. } => {}
+                MpvEvent::Event { name, .. } if name == "seek" => break,
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+
+        inner.set_connected(false);
+        assert!(matches!(events.recv().await, Ok(MpvEvent::Disconnected)));
+    }
+}
@@ -1,5 +1,6 @@
 use std::io::Result;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::time::{Duration, sleep};
 
 #[cfg(unix)]
 type Inner = tokio::net::UnixStream;
@@ -7,8 +8,12 @@ type Inner = tokio::net::UnixStream;
 #[cfg(windows)]
 type Inner = tokio::net::windows::named_pipe::NamedPipeClient;
 
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(250);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5);
+
 pub struct MpvStream {
-    reader: BufReader<tokio::io::ReadHalf<Inner>>,
+    path: String,
+    reader: Lines<BufReader<tokio::io::ReadHalf<Inner>>>,
     writer: tokio::io::WriteHalf<Inner>,
 }
 
@@ -17,13 +22,37 @@ impl MpvStream {
         let stream = Self::connect_inner(path).await?;
         let (reader, writer) = tokio::io::split(stream);
         Ok(Self {
-            reader: BufReader::new(reader),
+            path: path.to_string(),
+            reader: BufReader::new(reader).lines(),
             writer,
         })
     }
 
-    pub async fn read_line(&mut self, buf: &mut String) -> Result<usize> {
-        self.reader.read_line(buf).await
+    /// Re-opens the IPC connection, retrying with exponential backoff until
+    /// mpv accepts it again.
+    pub async fn reconnect(&mut self) {
+        let mut delay = RECONNECT_MIN_DELAY;
+        loop {
+            sleep(delay).await;
+            match Self::connect_inner(&self.path).await {
+                Ok(stream) => {
+                    let (reader, writer) = tokio::io::split(stream);
+                    self.reader = BufReader::new(reader).lines();
+                    self.writer = writer;
+                    return;
+                }
+                Err(e) => {
+                    log::debug!("[mpv] Reconnect failed, retrying in {:?}: {}", delay, e);
+                    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+                }
+            }
+        }
+    }
+
+    /// Reads the next line, or `None` on EOF. Cancel safe, so it can be used
+    /// as a `select!` branch.
+    pub async fn next_line(&mut self) -> Result<Option<String>> {
+        self.reader.next_line().await
     }
 
     pub async fn write_all(&mut self, buf: &[u8]) -> Result<()> {
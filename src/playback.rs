@@ -0,0 +1,71 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::time::Duration;
+
+/// Observed for `PlaybackState` in addition to `OBSERVED_PROPERTIES`, which
+/// already cover the path, title, track ids and `sub-delay`.
+pub const PLAYBACK_PROPERTIES: [&str; 3] = ["pause", "time-pos", "duration"];
+
+/// Minimum time between two `playback_state` broadcasts. `time-pos` changes
+/// every frame, so changes are coalesced.
+pub const PLAYBACK_STATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What mpv is playing and where, as pushed to clients.
+#[derive(Clone, Default, Serialize)]
+pub struct PlaybackState {
+    pub paused: Option<bool>,
+    pub time_pos: Option<f64>,
+    pub duration: Option<f64>,
+    pub media_title: Option<String>,
+    pub media_path: Option<String>,
+    pub sid: Option<i64>,
+    pub aid: Option<i64>,
+    pub sub_delay: Option<f64>,
+}
+
+/// Playhead as of the latest property change, unthrottled, for tasks that
+/// wait for playback to reach a position.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Position {
+    pub time_pos: Option<f64>,
+    pub paused: bool,
+}
+
+impl PlaybackState {
+    pub fn position(&self) -> Position {
+        Position {
+            time_pos: self.time_pos,
+            paused: self.paused.unwrap_or(false),
+        }
+    }
+
+    /// Applies a property change. Returns whether anything changed.
+    pub fn apply(&mut self, name: &str, data: &Value) -> bool {
+        match name {
+            "pause" => update(&mut self.paused, data.as_bool()),
+            "time-pos" => update(&mut self.time_pos, data.as_f64()),
+            "duration" => update(&mut self.duration, data.as_f64()),
+            "media-title" => update_str(&mut self.media_title, data.as_str()),
+            "path" => update_str(&mut self.media_path, data.as_str()),
+            "sid" => update(&mut self.sid, data.as_i64()),
+            "aid" => update(&mut self.aid, data.as_i64()),
+            "sub-delay" => update(&mut self.sub_delay, data.as_f64()),
+            _ => false,
+        }
+    }
+}
+
+fn update<T: PartialEq>(field: &mut T, value: T) -> bool {
+    let changed = *field != value;
+    *field = value;
+    changed
+}
+
+/// Like `update`, but only allocates when the string changed.
+fn update_str(field: &mut Option<String>, value: Option<&str>) -> bool {
+    if field.as_deref() == value {
+        return false;
+    }
+    *field = value.map(|s| s.to_string());
+    true
+}
@@ -0,0 +1,89 @@
+//! Query matching shared by the in-memory subtitle store and the history
+//! database. Matching is case-insensitive by Unicode lowercase. Both keep a
+//! trigram index that only narrows down candidates; every candidate is then
+//! checked with `SearchQuery::matches`, so both follow the same rule.
+
+use serde::Deserialize;
+use std::collections::HashSet;
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    /// The whole query must appear as-is.
+    #[default]
+    Substring,
+    /// Every whitespace-separated term must appear, in any order.
+    Tokens,
+}
+
+impl SearchMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SearchMode::Substring => "substring",
+            SearchMode::Tokens => "tokens",
+        }
+    }
+}
+
+/// Shortest term a trigram index can look up.
+const TRIGRAM_LEN: usize = 3;
+
+/// Three consecutive characters of folded text.
+pub type Trigram = [char; TRIGRAM_LEN];
+
+fn fold(text: &str) -> String {
+    text.to_lowercase()
+}
+
+/// Distinct trigrams of `text` after folding.
+pub fn trigrams(text: &str) -> HashSet<Trigram> {
+    let chars: Vec<char> = fold(text).chars().collect();
+    chars
+        .windows(TRIGRAM_LEN)
+        .map(|w| [w[0], w[1], w[2]])
+        .collect()
+}
+
+pub struct SearchQuery {
+    terms: Vec<String>,
+}
+
+impl SearchQuery {
+    pub fn new(query: &str, mode: SearchMode) -> Self {
+        let terms = match mode {
+            SearchMode::Substring => vec![fold(query.trim())],
+            SearchMode::Tokens => query.split_whitespace().map(fold).collect(),
+        };
+        Self {
+            terms: terms.into_iter().filter(|t| !t.is_empty()).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    pub fn matches(&self, text: &str) -> bool {
+        let text = fold(text);
+        self.terms.iter().all(|term| text.contains(term.as_str()))
+    }
+
+    /// FTS5 query requiring every term long enough for a trigram index,
+    /// each quoted as a phrase so it matches as a plain substring. `None` if
+    /// all terms are shorter.
+    pub fn fts_match(&self) -> Option<String> {
+        let phrases: Vec<_> = self
+            .terms
+            .iter()
+            .filter(|term| term.chars().count() >= TRIGRAM_LEN)
+            .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+            .collect();
+        (!phrases.is_empty()).then(|| phrases.join(" AND "))
+    }
+
+    /// Trigrams every match contains, from the terms long enough to have
+    /// any. Empty if no term is.
+    pub fn trigrams(&self) -> HashSet<Trigram> {
+        self.terms.iter().flat_map(|term| trigrams(term)).collect()
+    }
+}
@@ -0,0 +1,458 @@
+use log::debug;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::event_loop::Subtitle;
+use crate::search::{self, SearchQuery, Trigram};
+
+/// Limits applied on every insert. Oldest subtitles go first; when there are
+/// too many files, the least recently updated file is dropped as a whole.
+/// Both limits are at least 1, so the line just inserted is always kept.
+/// Lines past `max_age` are also hidden from reads until the next insert
+/// drops them.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    pub max_entries_per_file: usize,
+    pub max_files: usize,
+    pub max_age: Option<Duration>,
+}
+
+struct Entry {
+    subtitle: Subtitle,
+    added: Instant,
+}
+
+struct FileEntry {
+    subtitles: BTreeMap<u64, Entry>,
+    last_updated: Instant,
+}
+
+/// Subtitles grouped by media file, ordered by id within each file.
+pub struct SubtitleStore {
+    policy: RetentionPolicy,
+    files: HashMap<String, FileEntry>,
+    /// Subtitle id -> key into `files`.
+    index: HashMap<u64, String>,
+    /// Trigram -> ids of the subtitles whose text contains it, for `search`.
+    trigrams: HashMap<Trigram, HashSet<u64>>,
+}
+
+/// Subtitles without a known media path are kept under this key.
+const UNKNOWN_FILE: &str = "";
+
+impl SubtitleStore {
+    pub fn new(policy: RetentionPolicy) -> Self {
+        Self {
+            policy,
+            files: HashMap::new(),
+            index: HashMap::new(),
+            trigrams: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Subtitle> {
+        let file = self.files.get(self.index.get(&id)?)?;
+        file.subtitles
+            .get(&id)
+            .filter(|e| !self.is_expired(e))
+            .map(|e| &e.subtitle)
+    }
+
+    pub fn insert(&mut self, subtitle: Subtitle) {
+        let key = subtitle
+            .media_path
+            .clone()
+            .unwrap_or_else(|| UNKNOWN_FILE.to_string());
+        let now = Instant::now();
+
+        self.remove(subtitle.id);
+        self.index.insert(subtitle.id, key.clone());
+        for trigram in search::trigrams(&subtitle.text) {
+            self.trigrams
+                .entry(trigram)
+                .or_default()
+                .insert(subtitle.id);
+        }
+        let file = self.files.entry(key.clone()).or_insert_with(|| FileEntry {
+            subtitles: BTreeMap::new(),
+            last_updated: now,
+        });
+        file.last_updated = now;
+        file.subtitles.insert(
+            subtitle.id,
+            Entry {
+                subtitle,
+                added: now,
+            },
+        );
+
+        self.evict(&key);
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Subtitle> {
+        let key = self.index.remove(&id)?;
+        let file = self.files.get_mut(&key)?;
+        let entry = file.subtitles.remove(&id)?;
+        if file.subtitles.is_empty() {
+            self.files.remove(&key);
+        }
+        self.unindex(&entry.subtitle);
+        Some(entry.subtitle)
+    }
+
+    /// Media paths with at least one subtitle, in path order. Subtitles
+    /// without a known media path are listed under `""`.
+    pub fn files(&self) -> Vec<&str> {
+        let mut files: Vec<_> = self
+            .files
+            .iter()
+            .filter(|(_, f)| self.live(f).next().is_some())
+            .map(|(key, _)| key.as_str())
+            .collect();
+        files.sort();
+        files
+    }
+
+    /// All subtitles of `media_path`, in id order.
+    pub fn file(&self, media_path: &str) -> impl Iterator<Item = &Subtitle> {
+        self.files
+            .get(media_path)
+            .into_iter()
+            .flat_map(|f| self.live(f))
+    }
+
+    /// Subtitles of `media_path` shown during `[start, end]` of media time,
+    /// i.e. with their capture-time `sub-delay` and `sub-speed` applied, in id
+    /// order.
+    pub fn range(&self, media_path: &str, start: f64, end: f64) -> Vec<&Subtitle> {
+        self.file(media_path)
+            .filter(|s| {
+                s.media_timing()
+                    .is_some_and(|(sub_start, sub_end)| sub_start <= end && sub_end >= start)
+            })
+            .collect()
+    }
+
+    /// Up to `limit` subtitles matching `query`, restricted to `media_path` if
+    /// given. Ordered by file, then id. Terms of three or more characters are
+    /// looked up in the trigram index; a query without one checks every line.
+    pub fn search(
+        &self,
+        query: &SearchQuery,
+        media_path: Option<&str>,
+        limit: usize,
+    ) -> Vec<&Subtitle> {
+        let in_file = |key: &str| media_path.is_none_or(|path| path == key);
+        let Some(ids) = self.candidates(query) else {
+            return self
+                .files()
+                .into_iter()
+                .filter(|key| in_file(key))
+                .flat_map(|key| self.file(key))
+                .filter(|s| query.matches(&s.text))
+                .take(limit)
+                .collect();
+        };
+
+        let mut found: Vec<_> = ids
+            .into_iter()
+            .filter(|id| self.index.get(id).is_some_and(|key| in_file(key)))
+            .filter_map(|id| self.get(id))
+            .filter(|s| query.matches(&s.text))
+            .collect();
+        found.sort_by_key(|s| (&self.index[&s.id], s.id));
+        found.truncate(limit);
+        found
+    }
+
+    /// Ids of the subtitles containing every trigram of `query`, or `None` if
+    /// it has no term long enough to have any.
+    fn candidates(&self, query: &SearchQuery) -> Option<Vec<u64>> {
+        let mut postings = Vec::new();
+        for trigram in query.trigrams() {
+            match self.trigrams.get(&trigram) {
+                Some(ids) => postings.push(ids),
+                None => return Some(Vec::new()),
+            }
+        }
+        postings.sort_by_key(|ids| ids.len());
+        let (smallest, rest) = postings.split_first()?;
+        Some(
+            smallest
+                .iter()
+                .filter(|id| rest.iter().all(|ids| ids.contains(id)))
+                .copied()
+                .collect(),
+        )
+    }
+
+    fn unindex(&mut self, subtitle: &Subtitle) {
+        for trigram in search::trigrams(&subtitle.text) {
+            if let Some(ids) = self.trigrams.get_mut(&trigram) {
+                ids.remove(&subtitle.id);
+                if ids.is_empty() {
+                    self.trigrams.remove(&trigram);
+                }
+            }
+        }
+    }
+
+    fn is_expired(&self, entry: &Entry) -> bool {
+        self.policy
+            .max_age
+            .is_some_and(|max_age| entry.added.elapsed() > max_age)
+    }
+
+    /// Subtitles of `file` that haven't expired, in id order.
+    fn live<'a>(&'a self, file: &'a FileEntry) -> impl Iterator<Item = &'a Subtitle> {
+        file.subtitles
+            .values()
+            .filter(|e| !self.is_expired(e))
+            .map(|e| &e.subtitle)
+    }
+
+    /// Applies the retention policy. `current` is the file that was just
+    /// written to and is never dropped as a whole.
+    fn evict(&mut self, current: &str) {
+        let mut evicted = Vec::new();
+
+        if let Some(max_age) = self.policy.max_age {
+            for file in self.files.values_mut() {
+                while let Some(entry) = file.subtitles.first_entry()
+                    && entry.get().added.elapsed() > max_age
+                {
+                    evicted.push(entry.remove().subtitle);
+                }
+            }
+        }
+
+        if let Some(file) = self.files.get_mut(current) {
+            while file.subtitles.len() > self.policy.max_entries_per_file.max(1) {
+                let Some((_, entry)) = file.subtitles.pop_first() else {
+                    break;
+                };
+                evicted.push(entry.subtitle);
+            }
+        }
+
+        while self.files.len() > self.policy.max_files.max(1) {
+            let Some(oldest) = self
+                .files
+                .iter()
+                .filter(|(key, _)| key.as_str() != current)
+                .min_by_key(|(_, f)| f.last_updated)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            debug!("[store] Dropping subtitles of {}", oldest);
+            if let Some(file) = self.files.remove(&oldest) {
+                evicted.extend(file.subtitles.into_values().map(|e| e.subtitle));
+            }
+        }
+
+        self.files.retain(|_, f| !f.subtitles.is_empty());
+        for subtitle in &evicted {
+            self.index.remove(&subtitle.id);
+            self.unindex(subtitle);
+        }
+        if !evicted.is_empty() {
+            debug!("[store] Evicted {} subtitles", evicted.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_loop::TimingOffsets;
+    use crate::search::SearchMode;
+
+    fn subtitle(id: u64, media_path: &str) -> Subtitle {
+        Subtitle {
+            id,
+            text: format!("line {}", id),
+            html: None,
+            sub_start: Some(id as f64),
+            sub_end: Some(id as f64 + 1.0),
+            media_path: Some(media_path.to_string()),
+            media_title: None,
+            aid: None,
+            audio_source: None,
+            sid: None,
+            translation: None,
+            offsets: TimingOffsets::default(),
+        }
+    }
+
+    fn limited(max_entries_per_file: usize, max_files: usize) -> SubtitleStore {
+        SubtitleStore::new(RetentionPolicy {
+            max_entries_per_file,
+            max_files,
+            max_age: None,
+        })
+    }
+
+    fn ids(store: &SubtitleStore, media_path: &str) -> Vec<u64> {
+        store.file(media_path).map(|s| s.id).collect()
+    }
+
+    #[test]
+    fn oldest_lines_of_a_full_file_are_evicted() {
+        let mut store = limited(2, 10);
+        for id in 1..=3 {
+            store.insert(subtitle(id, "/a.mkv"));
+        }
+
+        assert_eq!(ids(&store, "/a.mkv"), [2, 3]);
+        assert!(store.get(1).is_none());
+    }
+
+    #[test]
+    fn least_recently_updated_file_is_dropped_but_never_the_current_one() {
+        let mut store = limited(10, 2);
+        store.insert(subtitle(1, "/a.mkv"));
+        store.insert(subtitle(2, "/b.mkv"));
+        store.insert(subtitle(3, "/a.mkv"));
+        store.insert(subtitle(4, "/c.mkv"));
+
+        assert!(ids(&store, "/b.mkv").is_empty());
+        assert!(store.get(2).is_none());
+        assert_eq!(ids(&store, "/a.mkv"), [1, 3]);
+        assert_eq!(ids(&store, "/c.mkv"), [4]);
+
+        let mut store = limited(10, 0);
+        store.insert(subtitle(1, "/a.mkv"));
+        store.insert(subtitle(2, "/b.mkv"));
+        assert_eq!(ids(&store, "/b.mkv"), [2]);
+    }
+
+    #[test]
+    fn lines_older_than_max_age_are_evicted_from_every_file() {
+        let mut store = SubtitleStore::new(RetentionPolicy {
+            max_entries_per_file: 10,
+            max_files: 10,
+            max_age: Some(Duration::from_millis(20)),
+        });
+        store.insert(subtitle(1, "/a.mkv"));
+        store.insert(subtitle(2, "/b.mkv"));
+        std::thread::sleep(Duration::from_millis(30));
+        store.insert(subtitle(3, "/b.mkv"));
+
+        assert!(ids(&store, "/a.mkv").is_empty());
+        assert_eq!(ids(&store, "/b.mkv"), [3]);
+        assert!(store.get(1).is_none());
+        assert!(store.get(2).is_none());
+    }
+
+    #[test]
+    fn expired_lines_are_hidden_before_the_next_insert() {
+        let mut store = SubtitleStore::new(RetentionPolicy {
+            max_entries_per_file: 10,
+            max_files: 10,
+            max_age: Some(Duration::from_millis(20)),
+        });
+        store.insert(subtitle(1, "/a.mkv"));
+        std::thread::sleep(Duration::from_millis(30));
+
+        assert!(store.get(1).is_none());
+        assert!(ids(&store, "/a.mkv").is_empty());
+        assert!(store.range("/a.mkv", 0.0, 10.0).is_empty());
+    }
+
+    #[test]
+    fn zero_entries_per_file_still_keeps_the_newest_line() {
+        let mut store = limited(0, 10);
+        store.insert(subtitle(1, "/a.mkv"));
+        store.insert(subtitle(2, "/a.mkv"));
+
+        assert_eq!(ids(&store, "/a.mkv"), [2]);
+        assert!(store.get(2).is_some());
+    }
+
+    fn with_text(id: u64, media_path: &str, text: &str) -> Subtitle {
+        Subtitle {
+            text: text.to_string(),
+            ..subtitle(id, media_path)
+        }
+    }
+
+    fn found(store: &SubtitleStore, query: &str, media_path: Option<&str>) -> Vec<u64> {
+        store
+            .search(
+                &SearchQuery::new(query, SearchMode::Substring),
+                media_path,
+                10,
+            )
+            .iter()
+            .map(|s| s.id)
+            .collect()
+    }
+
+    #[test]
+    fn range_is_in_media_time() {
+        let mut store = limited(10, 10);
+        store.insert(Subtitle {
+            offsets: TimingOffsets {
+                sub_delay: 10.0,
+                ..TimingOffsets::default()
+            },
+            ..subtitle(1, "/a.mkv")
+        });
+
+        assert!(store.range("/a.mkv", 0.0, 5.0).is_empty());
+        assert_eq!(store.range("/a.mkv", 11.5, 11.5).len(), 1);
+    }
+
+    #[test]
+    fn files_are_listed_in_path_order() {
+        let mut store = limited(10, 10);
+        store.insert(subtitle(1, "/b.mkv"));
+        store.insert(subtitle(2, "/a.mkv"));
+        store.insert(Subtitle {
+            media_path: None,
+            ..subtitle(3, "")
+        });
+
+        assert_eq!(store.files(), ["", "/a.mkv", "/b.mkv"]);
+        store.remove(2);
+        assert_eq!(store.files(), ["", "/b.mkv"]);
+    }
+
+    #[test]
+    fn search_uses_the_index_and_keeps_file_order() {
+        let mut store = limited(10, 10);
+        store.insert(with_text(1, "/b.mkv", "Bonjour le monde"));
+        store.insert(with_text(2, "/a.mkv", "le MONDE entier"));
+        store.insert(with_text(3, "/a.mkv", "autre chose"));
+
+        let query = SearchQuery::new("monde", SearchMode::Substring);
+        let mut candidates = store.candidates(&query).unwrap();
+        candidates.sort();
+        assert_eq!(candidates, [1, 2]);
+
+        assert_eq!(found(&store, "monde", None), [2, 1]);
+        assert_eq!(found(&store, "monde", Some("/b.mkv")), [1]);
+        assert_eq!(found(&store, "absent", None), Vec::<u64>::new());
+        // Too short for a trigram: every line is checked.
+        assert!(
+            store
+                .candidates(&SearchQuery::new("le", SearchMode::Substring))
+                .is_none()
+        );
+        assert_eq!(found(&store, "le", None), [2, 1]);
+    }
+
+    #[test]
+    fn removed_and_evicted_lines_leave_the_index() {
+        let mut store = limited(1, 10);
+        store.insert(with_text(1, "/a.mkv", "premier"));
+        store.insert(with_text(2, "/a.mkv", "second"));
+        store.insert(with_text(3, "/b.mkv", "troisième"));
+        assert_eq!(found(&store, "premier", None), Vec::<u64>::new());
+
+        store.remove(2);
+        store.remove(3);
+        assert!(store.trigrams.is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+//! Study mode: pauses mpv at the end of each subtitle line, optionally only
+//! for lines worth stopping at.
+
+use serde::Serialize;
+
+use crate::event_loop::Subtitle;
+
+/// Pause this far before `sub-end`, so the line is still on screen.
+const PAUSE_LEAD: f64 = 0.05;
+
+/// A playhead this far outside the tracked line means the user seeked away.
+const SEEK_TOLERANCE: f64 = 1.0;
+
+#[derive(Clone, Default, Serialize)]
+pub struct StudySettings {
+    pub enabled: bool,
+    /// Only pause on lines longer than this many characters, not counting
+    /// whitespace.
+    pub min_length: Option<usize>,
+    /// Only pause on lines containing one of these words. Matched as
+    /// substrings, since CJK text has no word boundaries.
+    pub unknown_words: Vec<String>,
+}
+
+impl StudySettings {
+    fn should_pause(&self, text: &str) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if let Some(min) = self.min_length
+            && text.chars().filter(|c| !c.is_whitespace()).count() <= min
+        {
+            return false;
+        }
+        self.unknown_words.is_empty()
+            || self
+                .unknown_words
+                .iter()
+                .any(|w| !w.is_empty() && text.contains(w.as_str()))
+    }
+}
+
+/// Line the playhead is expected to stop at.
+#[derive(Default)]
+pub struct StudyTracker {
+    target: Option<(f64, f64)>,
+}
+
+impl StudyTracker {
+    /// Called for every newly captured line. Lines that don't qualify clear
+    /// the target, so a skipped line never pauses inside the next one.
+    pub fn on_subtitle(&mut self, sub: &Subtitle, settings: &StudySettings) {
+        self.target = sub
+            .media_timing()
+            .filter(|_| settings.should_pause(&sub.text));
+    }
+
+    /// Returns true once when `time_pos` reaches the end of the tracked line.
+    pub fn on_time_pos(&mut self, time_pos: f64) -> bool {
+        let Some((start, end)) = self.target else {
+            return false;
+        };
+        if time_pos < start - SEEK_TOLERANCE || time_pos > end + SEEK_TOLERANCE {
+            self.target = None;
+            return false;
+        }
+        if time_pos >= end - PAUSE_LEAD {
+            self.target = None;
+            return true;
+        }
+        false
+    }
+
+    pub fn reset(&mut self) {
+        self.target = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn min_length_pauses_only_on_longer_lines() {
+        let settings = StudySettings {
+            enabled: true,
+            min_length: Some(3),
+            unknown_words: Vec::new(),
+        };
+
+        assert!(!settings.should_pause("行くぞ"));
+        assert!(!settings.should_pause("行 く ぞ"));
+        assert!(settings.should_pause("諦めるな"));
+    }
+
+    #[test]
+    fn unknown_words_match_inside_text() {
+        let settings = StudySettings {
+            enabled: true,
+            min_length: None,
+            unknown_words: vec!["諦".to_string()],
+        };
+
+        assert!(settings.should_pause("諦めるな"));
+        assert!(!settings.should_pause("行くぞ"));
+    }
+}
@@ -0,0 +1,90 @@
+//! Whole-track subtitle extraction: parses the SRT ffmpeg converts mpv's
+//! active subtitle track to.
+
+pub struct Cue {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+/// Cues that are empty once markup is stripped, or end before they start, are
+/// skipped.
+pub fn parse_srt(srt: &str) -> Vec<Cue> {
+    let srt = srt.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+    srt.split("\n\n")
+        .filter_map(|block| {
+            let mut lines = block.lines().skip_while(|l| !l.contains("-->"));
+            let (start, end) = lines.next()?.split_once("-->")?;
+            let start = parse_timestamp(start.trim())?;
+            let end = parse_timestamp(end.split_whitespace().next()?)?;
+            if end < start {
+                return None;
+            }
+            let text = strip_tags(&lines.collect::<Vec<_>>().join("\n"));
+            let text = text.trim();
+            (!text.is_empty()).then(|| Cue {
+                start,
+                end,
+                text: text.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Parses `HH:MM:SS,mmm` (or `.mmm`) into seconds.
+fn parse_timestamp(s: &str) -> Option<f64> {
+    let (hms, millis) = s.split_once([',', '.'])?;
+    let mut parts = hms.split(':').map(|p| p.trim().parse::<u64>().ok());
+    let (h, m, sec) = (parts.next()??, parts.next()??, parts.next()??);
+    let millis: u64 = millis.trim().parse().ok()?;
+    Some((h * 3600 + m * 60 + sec) as f64 + millis as f64 / 1000.0)
+}
+
+/// Drops `<font>`/`<i>`-style markup and leftover ASS override blocks so the
+/// text matches what mpv reports as `sub-text`.
+fn strip_tags(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut closing = None;
+    for c in text.chars() {
+        match (closing, c) {
+            (None, '<') => closing = Some('>'),
+            (None, '{') => closing = Some('}'),
+            (Some(end), c) if c == end => closing = None,
+            (Some(_), _) => {}
+            (None, c) => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cues_and_strips_markup() {
+        let srt = "\u{feff}1\r\n00:00:01,000 --> 00:00:02,500\r\n<i>こんにちは</i>\r\n\r\n\
+                   2\r\n01:02:03.045 --> 01:02:04.000 X1:0\r\n{\\an8}世界\r\nです\r\n";
+        let cues = parse_srt(srt);
+
+        let parsed: Vec<_> = cues
+            .iter()
+            .map(|c| (c.start, c.end, c.text.as_str()))
+            .collect();
+        assert_eq!(
+            parsed,
+            [(1.0, 2.5, "こんにちは"), (3723.045, 3724.0, "世界\nです")]
+        );
+    }
+
+    #[test]
+    fn skips_empty_reversed_and_malformed_cues() {
+        let srt = "1\n00:00:01,000 --> 00:00:02,000\n<b></b>\n\n\
+                   2\n00:00:05,000 --> 00:00:04,000\nreversed\n\n\
+                   3\n00:00:05 --> 00:00:06,000\nno millis\n\n\
+                   4\n00:00:07,000 --> 00:00:07,000\ninstant\n";
+        let texts: Vec<_> = parse_srt(srt).into_iter().map(|c| c.text).collect();
+
+        assert_eq!(texts, ["instant"]);
+    }
+}
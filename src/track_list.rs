@@ -0,0 +1,99 @@
+//! Resolves tracks in mpv's `track-list` property to the file and stream
+//! ffmpeg has to read.
+
+use log::debug;
+use serde::Deserialize;
+
+/// Image-based formats ffmpeg can't convert to text.
+const BITMAP_CODECS: [&str; 3] = ["hdmv_pgs_subtitle", "dvd_subtitle", "dvb_subtitle"];
+
+/// Where ffmpeg reads a track from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackSource {
+    pub input_path: String,
+    /// ffmpeg `-map` specifier, e.g. `0:3`.
+    pub stream: String,
+}
+
+impl TrackSource {
+    /// Guess used when mpv's `track-list` isn't known: audio track `aid` is
+    /// the `aid`-th audio stream of `media_path`.
+    pub fn nth_audio(media_path: &str, aid: i64) -> Self {
+        Self {
+            input_path: media_path.to_string(),
+            stream: format!("0:a:{}", (aid - 1).max(0)),
+        }
+    }
+}
+
+/// One entry of mpv's `track-list` property.
+#[derive(Deserialize)]
+struct TrackEntry {
+    id: i64,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    external: bool,
+    #[serde(rename = "external-filename")]
+    external_filename: Option<String>,
+    #[serde(rename = "ff-index")]
+    ff_index: Option<i64>,
+    codec: Option<String>,
+}
+
+fn find_track(track_list: &serde_json::Value, kind: &str, id: i64) -> Option<TrackEntry> {
+    let tracks: Vec<TrackEntry> = serde_json::from_value(track_list.clone()).ok()?;
+    tracks.into_iter().find(|t| t.kind == kind && t.id == id)
+}
+
+/// Maps a track to its input file. External tracks live in their own file,
+/// where `ff-index` (if known) is relative to that file.
+fn source_of(track: TrackEntry, media_path: &str) -> Option<TrackSource> {
+    let (input_path, stream) = if track.external {
+        let stream = track.ff_index.map_or_else(
+            || format!("0:{}:0", &track.kind[..1]),
+            |i| format!("0:{}", i),
+        );
+        (track.external_filename?, stream)
+    } else {
+        (media_path.to_string(), format!("0:{}", track.ff_index?))
+    };
+    Some(TrackSource { input_path, stream })
+}
+
+/// Finds the subtitle track `sid` in `track_list`. Returns `None` for bitmap
+/// tracks, network streams, or tracks ffmpeg can't address.
+pub fn resolve_subtitle(
+    track_list: &serde_json::Value,
+    sid: i64,
+    media_path: &str,
+) -> Option<TrackSource> {
+    let track = find_track(track_list, "sub", sid)?;
+
+    if let Some(codec) = track.codec.as_deref()
+        && BITMAP_CODECS.contains(&codec)
+    {
+        debug!("[track] Skipping bitmap subtitle track {} ({})", sid, codec);
+        return None;
+    }
+
+    let source = source_of(track, media_path)?;
+    if source.input_path.contains("://") {
+        debug!(
+            "[track] Skipping network subtitle source {}",
+            source.input_path
+        );
+        return None;
+    }
+    Some(source)
+}
+
+/// Finds the audio track `aid` in `track_list`, which may be in a separate
+/// file (`--audio-file`, external dubs).
+pub fn resolve_audio(
+    track_list: &serde_json::Value,
+    aid: i64,
+    media_path: &str,
+) -> Option<TrackSource> {
+    source_of(find_track(track_list, "audio", aid)?, media_path)
+}